@@ -0,0 +1,324 @@
+use std::ops::Not;
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http,
+    response::{IntoResponse, Response},
+};
+use facet::Facet;
+
+use crate::{
+    Ctx, audit,
+    auth::{self, ClientInfo, Session},
+    json::Json,
+    neo4j,
+};
+
+#[derive(Facet, Clone, Copy)]
+struct DeleteReq<'inp> {
+    #[facet(default)]
+    password: Option<&'inp str>,
+}
+
+#[derive(serde::Serialize)]
+pub struct Receipt {
+    username: String,
+    requested_on: jiff::Timestamp,
+    // `None` si ya se borro todo
+    purge_on: Option<jiff::Timestamp>,
+    // Solo se muestra esta vez, sirve para cancelar el borrado antes de `purge_on`
+    restore_token: Option<String>,
+    likes: i64,
+    matches: i64,
+    api_keys: i64,
+    sessions: usize,
+}
+
+pub async fn delete_account(
+    State(ctx): State<Ctx>,
+    client: ClientInfo,
+    session: Session,
+    bytes: Bytes,
+) -> Result<axum::Json<Receipt>, Response> {
+    // Las cuentas sin contraseña pueden mandar el cuerpo vacio
+    let req = if bytes.is_empty() {
+        DeleteReq { password: None }
+    } else {
+        let Json(req): Json<DeleteReq> =
+            Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+        req
+    };
+
+    let authorized = auth::reauthenticate(&ctx, &session, &client, req.password).await?;
+
+    if authorized.not() {
+        ctx.audit
+            .record(audit::Event::new(
+                audit::Kind::AccountDeleted,
+                audit::Outcome::Failure,
+                &session.username,
+                &client,
+            ))
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        Err(http::StatusCode::FORBIDDEN.into_response())?
+    }
+
+    let username_key = auth::lookup_key(&session.username);
+    let requested_on = jiff::Timestamp::now();
+
+    // Las API keys se borran desde ya, son credenciales igual que las sesiones
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                })
+                WITH u,
+                    COUNT { (u)-[:LIKES]->() } AS likes,
+                    COUNT { (u)-[:MATCHES]-() } AS matches
+                OPTIONAL MATCH (u)-[:OWNS]->(k:ApiKey)
+                WITH likes, matches, collect(k) AS keys
+                FOREACH (k IN keys | DETACH DELETE k)
+                RETURN likes, matches, size(keys) AS api_keys"#,
+            ))
+            .param("username_key", username_key.as_str()),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let Some(row) = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+    else {
+        Err(http::StatusCode::NOT_FOUND.into_response())?
+    };
+
+    let sessions = auth::revoke_user_sessions(&ctx, &session.username, None)
+        .await
+        .map_err(|res| res.into_response())?;
+
+    ctx.audit
+        .record(audit::Event::new(
+            audit::Kind::AccountDeleted,
+            audit::Outcome::Success,
+            &session.username,
+            &client,
+        ))
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let mut receipt = Receipt {
+        username: session.username.clone(),
+        requested_on,
+        purge_on: None,
+        restore_token: None,
+        likes: row.get("likes").unwrap_or_default(),
+        matches: row.get("matches").unwrap_or_default(),
+        api_keys: row.get("api_keys").unwrap_or_default(),
+        sessions,
+    };
+
+    let Some(grace) = ctx.auth.account_deletion_grace else {
+        purge_user(&ctx, &username_key)
+            .await
+            .map_err(neo4j::Error::from)
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        tracing::info!("Deleted account {username}", username = session.username);
+
+        return Ok(axum::Json(receipt));
+    };
+
+    let purge_on = requested_on
+        .checked_add(grace)
+        .map_err(|_err| http::StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    let restore_token = auth::generate_random_token::<50>();
+
+    // Mientras tanto la cuenta no puede iniciar sesion, la purga la hace
+    // `sweep_deleted`
+    ctx.neo4j
+        .run(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                })
+                SET u.deletion_requested_on = $requested_on,
+                    u.deletion_due = $purge_on,
+                    u.restore_token = $restore_token"#,
+            ))
+            .param("username_key", username_key.as_str())
+            .param("requested_on", requested_on.as_second())
+            .param("purge_on", purge_on.as_second())
+            .param("restore_token", auth::hash_token(&ctx, &restore_token)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    tracing::info!(
+        "Scheduled deletion of {username} for {purge_on}",
+        username = session.username
+    );
+
+    receipt.purge_on = Some(purge_on);
+    receipt.restore_token = Some(restore_token);
+
+    Ok(axum::Json(receipt))
+}
+
+#[derive(Facet, Clone, Copy)]
+struct RestoreReq<'inp> {
+    restore_token: &'inp str,
+}
+
+pub async fn restore_account(
+    State(ctx): State<Ctx>,
+    bytes: Bytes,
+) -> Result<http::StatusCode, Response> {
+    let json @ Json(req): Json<RestoreReq> =
+        Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+
+    if json.is_all_str_set().not() {
+        Err((http::StatusCode::BAD_REQUEST).into_response())?;
+    }
+
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    restore_token: $restore_token
+                })
+                WHERE u.deletion_due > $now
+                REMOVE u.deletion_requested_on, u.deletion_due, u.restore_token
+                RETURN u.username AS username"#,
+            ))
+            .param("restore_token", auth::hash_token(&ctx, req.restore_token))
+            .param("now", jiff::Timestamp::now().as_second()),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let Some(row) = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+    else {
+        Err(http::StatusCode::NOT_FOUND.into_response())?
+    };
+
+    tracing::info!(
+        "Restored account {username}",
+        username = row.get::<String>("username").unwrap_or_default()
+    );
+
+    Ok(http::StatusCode::NO_CONTENT)
+}
+
+// DETACH DELETE se lleva los LIKES y MATCHES en ambas direcciones, las API
+// keys ya se borraron en `delete_account`. Los avatares solo se borran si
+// ningun otro usuario subio el mismo archivo
+async fn purge_user(ctx: &Ctx, username_key: &str) -> Result<(), neo4rs::Error> {
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                })
+                WITH u, [url IN [u.avatar, u.avatar_thumbnail]
+                    WHERE url CONTAINS '/media/' | split(url, '/media/')[1]] AS hashes
+                DETACH DELETE u
+                WITH hashes
+                UNWIND hashes AS hash
+                OPTIONAL MATCH (other:User)
+                WHERE other.avatar ENDS WITH '/media/' + hash
+                    OR other.avatar_thumbnail ENDS WITH '/media/' + hash
+                WITH hash, count(other) AS users
+                WHERE users = 0
+                RETURN hash"#,
+            ))
+            .param("username_key", username_key),
+        )
+        .await?;
+
+    while let Some(row) = stream.next().await? {
+        let hash: String = row.get("hash").unwrap_or_default();
+        if let Err(err) = ctx.media.remove(&hash).await {
+            tracing::error!("Failed removing media {hash} of {username_key} {err:?}");
+        }
+    }
+
+    match ctx.audit.forget(username_key).await {
+        Ok(removed) => tracing::debug!("Removed {removed} audit events of {username_key}"),
+        Err(err) => tracing::error!("Failed removing audit events of {username_key} {err:?}"),
+    }
+
+    Ok(())
+}
+
+pub async fn sweep_deleted(ctx: Ctx, every: std::time::Duration) {
+    let mut interval = tokio::time::interval(every);
+
+    loop {
+        interval.tick().await;
+
+        let due = ctx
+            .neo4j
+            .execute(
+                neo4rs::Query::new(String::from(
+                    r#"MATCH (u:User)
+                    WHERE u.deletion_due <= $now
+                    RETURN u.username_key AS username_key"#,
+                ))
+                .param("now", jiff::Timestamp::now().as_second()),
+            )
+            .await;
+
+        let mut due = match due {
+            Ok(stream) => stream,
+            Err(err) => {
+                tracing::error!("Account sweep failed {err:?}");
+                continue;
+            }
+        };
+
+        let mut purged = 0;
+        loop {
+            let username_key = match due.next().await {
+                Ok(Some(row)) => row.get::<String>("username_key").unwrap_or_default(),
+                Ok(None) => break,
+                Err(err) => {
+                    tracing::error!("Account sweep failed {err:?}");
+                    break;
+                }
+            };
+
+            match purge_user(&ctx, &username_key).await {
+                Ok(()) => purged += 1,
+                Err(err) => tracing::error!("Failed purging {username_key} {err:?}"),
+            }
+        }
+
+        if purged > 0 {
+            tracing::info!("Purged {purged} deleted accounts");
+        }
+    }
+}
@@ -0,0 +1,492 @@
+use std::ops::Not;
+
+use axum::{
+    body::Bytes,
+    extract::{FromRequestParts, OptionalFromRequestParts, Path, State},
+    http,
+    response::{IntoResponse, Response},
+};
+use facet::Facet;
+
+use crate::{
+    Ctx, audit,
+    auth::{self, ClientInfo, Session},
+    json::Json,
+    neo4j,
+};
+
+// Asi se distingue una API key de un token de sesion sin ir al store
+const PREFIX: &str = "orb_";
+// `last_used_on` solo se reescribe si es mas viejo que esto
+const LAST_USED_RESOLUTION: jiff::SignedDuration = jiff::SignedDuration::from_secs(60);
+
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    #[serde(rename = "read")]
+    Read,
+    #[serde(rename = "write")]
+    Write,
+    #[serde(rename = "taxonomy:write")]
+    TaxonomyWrite,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+            Scope::TaxonomyWrite => "taxonomy:write",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read" => Some(Scope::Read),
+            "write" => Some(Scope::Write),
+            "taxonomy:write" => Some(Scope::TaxonomyWrite),
+            _ => None,
+        }
+    }
+}
+
+// Rutas que manejan la cuenta, con una API key no se pueden usar
+const SESSION_ONLY: &[&str] = &[
+    "/me/sessions",
+    "/me/password",
+    "/me/totp",
+    "/me/api-keys",
+    "/me/export",
+    "/me/security-events",
+    "/admin",
+];
+
+// Rutas POST que solo leen
+const READ_ONLY_POSTS: &[&str] = &[
+    "/category/search",
+    "/genre/search",
+    "/other",
+    "/other/matches",
+    "/other/interest",
+    "/other/search",
+    "/other/search/strict",
+    "/me/shortest-path",
+];
+
+// El scope que necesita una API key para una ruta protegida, `None` si la ruta
+// solo acepta sesiones
+pub fn required_scope(method: &http::Method, path: &str) -> Option<Scope> {
+    if SESSION_ONLY.iter().any(|prefix| path.starts_with(prefix)) {
+        return None;
+    }
+
+    // Borrar la cuenta pide la contraseña, no se hace con una key
+    if method == http::Method::DELETE && path == "/me" {
+        return None;
+    }
+
+    match (method, path) {
+        (&http::Method::POST, "/category" | "/genre") => Some(Scope::TaxonomyWrite),
+        (&http::Method::GET | &http::Method::HEAD, _) => Some(Scope::Read),
+        (&http::Method::POST, path) if READ_ONLY_POSTS.contains(&path) => Some(Scope::Read),
+        _ => Some(Scope::Write),
+    }
+}
+
+pub struct ApiKey {
+    id: String,
+    username: String,
+    key_hash: String,
+    scopes: Vec<Scope>,
+    created_on: jiff::Timestamp,
+}
+
+impl ApiKey {
+    pub fn allows(&self, method: &http::Method, path: &str) -> bool {
+        required_scope(method, path).is_some_and(|scope| self.scopes.contains(&scope))
+    }
+
+    // Los handlers siguen pidiendo una `Session`, la key se hace pasar por una
+    pub fn into_session(self) -> Session {
+        Session::from_api_key(self.id, self.key_hash, self.username, self.created_on)
+    }
+}
+
+// `None` si el bearer no es una API key, asi `protect_routes` puede probar con
+// una sesion normal
+impl OptionalFromRequestParts<Ctx> for ApiKey {
+    type Rejection = http::StatusCode;
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &Ctx,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        let Ok(axum_auth::AuthBearer(token)) =
+            axum_auth::AuthBearer::from_request_parts(parts, state).await
+        else {
+            return Ok(None);
+        };
+
+        if token.starts_with(PREFIX).not() {
+            return Ok(None);
+        }
+
+        let key_hash = auth::hash_token(state, &token);
+
+        let mut stream = state
+            .neo4j
+            .execute_read(
+                neo4rs::Query::new(String::from(
+                    r#"MATCH (u:User)-[:OWNS]->(k:ApiKey {
+                        key_hash: $key_hash
+                    })
+                    RETURN u.username AS username,
+                        k.id AS id,
+                        k.scopes AS scopes,
+                        k.created_on AS created_on,
+                        k.last_used_on AS last_used_on"#,
+                ))
+                .param("key_hash", key_hash.as_str()),
+            )
+            .await
+            .map_err(neo4j::Error::from)
+            .map_err(http::StatusCode::from)?;
+
+        let row = stream
+            .next()
+            .await
+            .map_err(neo4j::Error::from)
+            .map_err(http::StatusCode::from)?;
+
+        let Some(row) = row else {
+            Err(http::StatusCode::UNAUTHORIZED)?
+        };
+
+        // Un script que usa la key en cada request no debe escribir en cada
+        // uno, basta con saber el ultimo minuto en que se uso
+        let now = jiff::Timestamp::now();
+        let last_used_on = row
+            .get::<i64>("last_used_on")
+            .ok()
+            .and_then(|secs| jiff::Timestamp::from_second(secs).ok());
+        if last_used_on
+            .is_none_or(|last_used_on| last_used_on.duration_until(now) > LAST_USED_RESOLUTION)
+        {
+            tokio::spawn(touch(state.neo4j.clone(), key_hash.clone(), now));
+        }
+
+        let scopes: Vec<String> = row.get("scopes").unwrap_or_default();
+        let created_on: i64 = row.get("created_on").unwrap_or_default();
+
+        Ok(Some(ApiKey {
+            id: row.get("id").unwrap_or_default(),
+            username: row.get("username").unwrap_or_default(),
+            key_hash,
+            scopes: scopes
+                .iter()
+                .filter_map(|scope| Scope::parse(scope))
+                .collect(),
+            created_on: jiff::Timestamp::from_second(created_on).unwrap_or_default(),
+        }))
+    }
+}
+
+async fn touch(graph: neo4rs::Graph, key_hash: String, now: jiff::Timestamp) {
+    let touched = graph
+        .run(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (k:ApiKey {
+                    key_hash: $key_hash
+                })
+                WHERE coalesce(k.last_used_on, 0) < $now
+                SET k.last_used_on = $now"#,
+            ))
+            .param("key_hash", key_hash)
+            .param("now", now.as_second()),
+        )
+        .await;
+
+    if let Err(err) = touched {
+        tracing::error!("Failed updating API key last use {err:?}");
+    }
+}
+
+// Un reset de contraseña supone que alguien mas pudo tener la cuenta, las keys
+// que haya creado no deben sobrevivirlo
+pub async fn revoke_user_keys(ctx: &Ctx, username: &str) -> Result<i64, http::StatusCode> {
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (:User {
+                    username_key: $username_key
+                })-[:OWNS]->(k:ApiKey)
+                DETACH DELETE k
+                RETURN count(*) AS revoked"#,
+            ))
+            .param("username_key", auth::lookup_key(username)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)?;
+
+    Ok(row
+        .and_then(|row| row.get("revoked").ok())
+        .unwrap_or_default())
+}
+
+#[derive(Facet)]
+struct CreateReq<'inp> {
+    name: &'inp str,
+    scopes: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+pub struct CreatedKey {
+    id: String,
+    name: String,
+    // Solo se muestra esta vez, guardamos unicamente su HMAC
+    key: String,
+    scopes: Vec<Scope>,
+    created_on: jiff::Timestamp,
+}
+
+#[derive(serde::Serialize)]
+pub struct KeyInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_on: jiff::Timestamp,
+    pub last_used_on: Option<jiff::Timestamp>,
+}
+
+#[derive(serde::Serialize)]
+pub struct KeysResponse {
+    api_keys: Vec<KeyInfo>,
+}
+
+pub async fn create_api_key(
+    State(ctx): State<Ctx>,
+    client: ClientInfo,
+    session: Session,
+    bytes: Bytes,
+) -> Result<(http::StatusCode, axum::Json<CreatedKey>), Response> {
+    let json @ Json(req): Json<CreateReq> =
+        Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+
+    if json.is_all_str_set().not() || req.scopes.is_empty() {
+        Err((http::StatusCode::BAD_REQUEST).into_response())?;
+    }
+
+    let Some(scopes) = req
+        .scopes
+        .iter()
+        .map(|scope| Scope::parse(scope))
+        .collect::<Option<Vec<_>>>()
+    else {
+        Err(http::StatusCode::BAD_REQUEST.into_response())?
+    };
+
+    let id = auth::generate_random_token::<16>();
+    let key = format!("{PREFIX}{}", auth::generate_random_token::<40>());
+    let created_on = jiff::Timestamp::now();
+
+    ctx.neo4j
+        .run(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                })
+                CREATE (u)-[:OWNS]->(:ApiKey {
+                    id: $id,
+                    name: $name,
+                    key_hash: $key_hash,
+                    scopes: $scopes,
+                    created_on: $created_on
+                })"#,
+            ))
+            .param("username_key", auth::lookup_key(&session.username))
+            .param("id", id.as_str())
+            .param("name", req.name)
+            .param("key_hash", auth::hash_token(&ctx, &key))
+            .param(
+                "scopes",
+                scopes
+                    .iter()
+                    .map(|scope| scope.as_str())
+                    .collect::<Vec<_>>(),
+            )
+            .param("created_on", created_on.as_second()),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    ctx.audit
+        .record(audit::Event::new(
+            audit::Kind::ApiKeyCreated,
+            audit::Outcome::Success,
+            &session.username,
+            &client,
+        ))
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    Ok((
+        http::StatusCode::CREATED,
+        axum::Json(CreatedKey {
+            id,
+            name: req.name.to_string(),
+            key,
+            scopes,
+            created_on,
+        }),
+    ))
+}
+
+pub async fn list_api_keys(
+    State(ctx): State<Ctx>,
+    session: Session,
+) -> Result<axum::Json<KeysResponse>, Response> {
+    let api_keys = key_infos(&ctx, &session.username).await?;
+
+    Ok(axum::Json(KeysResponse { api_keys }))
+}
+
+// Lo que se puede mostrar de las keys de `username`, nunca el hash
+pub async fn key_infos(ctx: &Ctx, username: &str) -> Result<Vec<KeyInfo>, Response> {
+    let mut stream = ctx
+        .neo4j
+        .execute_read(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (:User {
+                    username_key: $username_key
+                })-[:OWNS]->(k:ApiKey)
+                RETURN k.id AS id,
+                    k.name AS name,
+                    k.scopes AS scopes,
+                    k.created_on AS created_on,
+                    k.last_used_on AS last_used_on
+                ORDER BY k.created_on"#,
+            ))
+            .param("username_key", auth::lookup_key(username)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let mut api_keys = Vec::new();
+    while let Some(row) = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+    {
+        let scopes: Vec<String> = row.get("scopes").unwrap_or_default();
+        let created_on: i64 = row.get("created_on").unwrap_or_default();
+        let last_used_on: Option<i64> = row.get("last_used_on").ok();
+
+        api_keys.push(KeyInfo {
+            id: row.get("id").unwrap_or_default(),
+            name: row.get("name").unwrap_or_default(),
+            scopes: scopes
+                .iter()
+                .filter_map(|scope| Scope::parse(scope))
+                .collect(),
+            created_on: jiff::Timestamp::from_second(created_on).unwrap_or_default(),
+            last_used_on: last_used_on.and_then(|secs| jiff::Timestamp::from_second(secs).ok()),
+        });
+    }
+
+    Ok(api_keys)
+}
+
+pub async fn revoke_api_key(
+    State(ctx): State<Ctx>,
+    client: ClientInfo,
+    session: Session,
+    Path(id): Path<String>,
+) -> Result<http::StatusCode, Response> {
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (:User {
+                    username_key: $username_key
+                })-[:OWNS]->(k:ApiKey {
+                    id: $id
+                })
+                DETACH DELETE k
+                RETURN count(*) AS revoked"#,
+            ))
+            .param("username_key", auth::lookup_key(&session.username))
+            .param("id", id.as_str()),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    if row.is_none_or(|row| row.get::<i64>("revoked").unwrap_or_default() == 0) {
+        Err(http::StatusCode::NOT_FOUND.into_response())?
+    }
+
+    ctx.audit
+        .record(audit::Event::new(
+            audit::Kind::ApiKeyRevoked,
+            audit::Outcome::Success,
+            &session.username,
+            &client,
+        ))
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    Ok(http::StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn account_routes_need_a_session() {
+        for path in [
+            "/me/sessions/abc",
+            "/me/password",
+            "/me/totp/confirm",
+            "/me/api-keys",
+        ] {
+            assert!(required_scope(&http::Method::GET, path).is_none());
+        }
+        assert!(required_scope(&http::Method::DELETE, "/me").is_none());
+    }
+
+    #[test]
+    fn scope_follows_method_and_route() {
+        let scope = |method, path| required_scope(&method, path);
+
+        assert!(scope(http::Method::GET, "/me/matches") == Some(Scope::Read));
+        assert!(scope(http::Method::HEAD, "/me") == Some(Scope::Read));
+        assert!(scope(http::Method::POST, "/other/search") == Some(Scope::Read));
+        assert!(scope(http::Method::POST, "/me/match") == Some(Scope::Write));
+        assert!(scope(http::Method::PATCH, "/me") == Some(Scope::Write));
+        assert!(scope(http::Method::POST, "/category") == Some(Scope::TaxonomyWrite));
+        assert!(scope(http::Method::POST, "/genre") == Some(Scope::TaxonomyWrite));
+    }
+}
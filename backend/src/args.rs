@@ -4,8 +4,38 @@ pub struct Args {
     pub port: u16,
     #[clap(short, long, env = "ADDRESS")]
     pub address: std::net::IpAddr,
+    // `/metrics` se sirve aparte y sin autenticacion para que Prometheus lo
+    // pueda leer, no hay que exponerlo fuera de la red interna
+    #[clap(long, env = "METRICS_ADDRESS")]
+    pub metrics_address: Option<std::net::SocketAddr>,
     #[clap(flatten)]
     pub neo4j: Neo4j,
+    #[clap(flatten)]
+    pub auth: Auth,
+    #[clap(flatten)]
+    pub mail: Mail,
+    #[clap(flatten)]
+    pub throttle: Throttle,
+    #[clap(flatten)]
+    pub media: Media,
+    #[clap(long, env = "SESSION_STORE", value_enum, default_value_t = SessionBackend::Sled)]
+    pub session_store: SessionBackend,
+    #[clap(long, env = "SLED_PATH", default_value = "/tmp/asdaksdj")]
+    pub sled_path: std::path::PathBuf,
+    // Cada provider es `name=...,issuer=...,client_id=...,client_secret=...`,
+    // en la variable de entorno se separan con `;`
+    #[clap(long = "oidc-provider", env = "OIDC_PROVIDERS", value_delimiter = ';')]
+    pub oidc_providers: Vec<OidcProvider>,
+    // Le da el rol de admin a este usuario y termina sin levantar el server
+    #[clap(long)]
+    pub grant_admin: Option<String>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum SessionBackend {
+    Sled,
+    Memory,
+    Neo4j,
 }
 
 #[derive(clap::Parser)]
@@ -18,6 +48,149 @@ pub struct Neo4j {
     pub neo_username: String,
 }
 
+#[derive(clap::Parser)]
+pub struct Auth {
+    #[clap(long, env = "ACCESS_TOKEN_TTL", default_value = "15m")]
+    pub access_token_ttl: jiff::SignedDuration,
+    #[clap(long, env = "SESSION_TTL", default_value = "1440h")]
+    pub session_ttl: jiff::SignedDuration,
+    #[clap(long, env = "SESSION_SWEEP_INTERVAL", default_value = "10m", value_parser = positive_duration)]
+    pub session_sweep_interval: jiff::SignedDuration,
+    #[clap(long, env = "TOKEN_HASH_KEY")]
+    pub token_hash_key: String,
+    #[clap(long, env = "PASSWORD_RESET_TTL", default_value = "30m")]
+    pub password_reset_ttl: jiff::SignedDuration,
+    #[clap(long, env = "VERIFY_TOKEN_TTL", default_value = "48h")]
+    pub verify_token_ttl: jiff::SignedDuration,
+    #[clap(long, env = "TOTP_CHALLENGE_TTL", default_value = "5m")]
+    pub totp_challenge_ttl: jiff::SignedDuration,
+    #[clap(long, env = "PASSWORD_MIN_LENGTH", default_value_t = 8)]
+    pub password_min_length: usize,
+    // Cuantas clases distintas (minusculas, mayusculas, digitos, simbolos)
+    #[clap(long, env = "PASSWORD_MIN_CLASSES", default_value_t = 2)]
+    pub password_min_classes: usize,
+    // Una contraseña por linea, se suma a la lista corta que viene incluida
+    #[clap(long, env = "COMMON_PASSWORDS_FILE")]
+    pub common_passwords_file: Option<std::path::PathBuf>,
+    // Base de los links que mandamos por correo
+    #[clap(long, env = "APP_URL", default_value = "http://localhost:5173")]
+    pub app_url: String,
+    #[clap(long, env = "API_URL", default_value = "http://localhost:6232")]
+    pub api_url: String,
+    #[clap(long, env = "UNVERIFIED_POLICY", value_enum, default_value_t = UnverifiedPolicy::NoMatching)]
+    pub unverified_policy: UnverifiedPolicy,
+    // Sesiones en cookies HttpOnly para el frontend web, el header
+    // `Authorization` se sigue aceptando. Las cookies son SameSite=Strict, el
+    // frontend y la API tienen que estar en el mismo sitio
+    #[clap(long, env = "COOKIE_SESSIONS")]
+    pub cookie_sessions: bool,
+    #[clap(long, env = "COOKIE_DOMAIN")]
+    pub cookie_domain: Option<String>,
+    // Sin valor `DELETE /me` borra todo al momento
+    #[clap(long, env = "ACCOUNT_DELETION_GRACE")]
+    pub account_deletion_grace: Option<jiff::SignedDuration>,
+}
+
+// Que puede hacer un usuario que todavia no verifica su correo
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum UnverifiedPolicy {
+    Allow,
+    NoMatching,
+    NoSignin,
+}
+
+#[derive(clap::Parser, Clone)]
+pub struct Throttle {
+    #[clap(long, env = "LOGIN_MAX_USER_FAILURES", default_value_t = 5)]
+    pub login_max_user_failures: u32,
+    #[clap(long, env = "LOGIN_MAX_IP_FAILURES", default_value_t = 20)]
+    pub login_max_ip_failures: u32,
+    #[clap(long, env = "LOGIN_LOCKOUT_BASE", default_value = "30s")]
+    pub login_lockout_base: jiff::SignedDuration,
+    #[clap(long, env = "LOGIN_LOCKOUT_MAX", default_value = "1h")]
+    pub login_lockout_max: jiff::SignedDuration,
+    // Tiempo sin fallos despues del cual se olvidan los intentos anteriores
+    #[clap(long, env = "LOGIN_FAILURE_WINDOW", default_value = "1h")]
+    pub login_failure_window: jiff::SignedDuration,
+}
+
+#[derive(clap::Parser)]
+pub struct Mail {
+    #[clap(long, env = "MAIL_SENDER", value_enum, default_value_t = MailBackend::Outbox)]
+    pub mail_sender: MailBackend,
+    #[clap(long, env = "SMTP_URL", required_if_eq("mail_sender", "smtp"))]
+    pub smtp_url: Option<String>,
+    #[clap(
+        long,
+        env = "MAIL_FROM",
+        default_value = "Orbitly <no-reply@orbitly.app>"
+    )]
+    pub mail_from: lettre::message::Mailbox,
+    #[clap(long, env = "MAIL_OUTBOX", default_value = "/tmp/orbitly-outbox")]
+    pub mail_outbox: std::path::PathBuf,
+}
+
+#[derive(clap::Parser)]
+pub struct Media {
+    #[clap(long, env = "MEDIA_DIR", default_value = "/tmp/orbitly-media")]
+    pub media_dir: std::path::PathBuf,
+    #[clap(long, env = "AVATAR_MAX_BYTES", default_value_t = 5 * 1024 * 1024)]
+    pub avatar_max_bytes: usize,
+    // Tope de ancho y alto al decodificar, evita imagenes que explotan en memoria
+    #[clap(long, env = "AVATAR_MAX_DIMENSION", default_value_t = 8192)]
+    pub avatar_max_dimension: u32,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy)]
+pub enum MailBackend {
+    Smtp,
+    Outbox,
+}
+
+#[derive(Clone)]
+pub struct OidcProvider {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl std::str::FromStr for OidcProvider {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut fields: std::collections::HashMap<&str, &str> = value
+            .split(',')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .collect();
+
+        let mut field = |key: &str| {
+            fields
+                .remove(key)
+                .map(String::from)
+                .ok_or_else(|| format!("missing `{key}` in OIDC provider"))
+        };
+
+        Ok(OidcProvider {
+            name: field("name")?,
+            issuer: field("issuer")?,
+            client_id: field("client_id")?,
+            client_secret: field("client_secret")?,
+        })
+    }
+}
+
+// `tokio::time::interval` entra en panico con un periodo de cero
+fn positive_duration(value: &str) -> Result<jiff::SignedDuration, String> {
+    let duration: jiff::SignedDuration = value.parse().map_err(|err| format!("{err}"))?;
+    if duration.is_positive() {
+        Ok(duration)
+    } else {
+        Err(String::from("must be greater than zero"))
+    }
+}
+
 impl Neo4j {
     pub fn to_config(self) -> neo4rs::Result<neo4rs::Config> {
         neo4rs::ConfigBuilder::new()
@@ -33,3 +206,40 @@ impl Args {
         <Self as clap::Parser>::parse()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oidc_provider_parses_every_field() {
+        let provider: OidcProvider =
+            "name=google, issuer=https://accounts.google.com,client_id=id,client_secret=a=b"
+                .parse()
+                .unwrap();
+
+        assert_eq!(provider.name, "google");
+        assert_eq!(provider.issuer, "https://accounts.google.com");
+        assert_eq!(provider.client_id, "id");
+        assert_eq!(provider.client_secret, "a=b");
+    }
+
+    #[test]
+    fn oidc_provider_reports_the_missing_field() {
+        let err = "name=google,issuer=https://accounts.google.com,client_id=id"
+            .parse::<OidcProvider>()
+            .err();
+
+        assert_eq!(
+            err.as_deref(),
+            Some("missing `client_secret` in OIDC provider")
+        );
+    }
+
+    #[test]
+    fn sweep_interval_must_be_positive() {
+        assert!(positive_duration("10m").is_ok());
+        assert!(positive_duration("0s").is_err());
+        assert!(positive_duration("-1m").is_err());
+    }
+}
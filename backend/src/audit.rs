@@ -0,0 +1,493 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use axum::{
+    extract::{Query, State},
+    http,
+    response::{IntoResponse, Response},
+};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    Ctx,
+    auth::{self, ClientInfo, Session},
+};
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+// Cuantas llaves se leen o borran cada vez que se toma el lock
+const SCAN_BATCH: usize = 500;
+// El identificador y el user agent los manda el cliente, no guardamos mas que
+// esto de cada uno
+const MAX_FIELD_LENGTH: usize = 256;
+// Un intento bloqueado por el throttle no revisa nada, basta con uno por IP en
+// este intervalo
+const LOCKED_INTERVAL: jiff::SignedDuration = jiff::SignedDuration::from_mins(1);
+
+#[derive(Debug)]
+pub enum Error {
+    Sled(std::io::Error),
+    Serde(serde_json::Error),
+    Cursor,
+}
+
+impl From<Error> for http::StatusCode {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::Sled(err) => tracing::error!("Failed accessing audit log tree {err:?}"),
+            Error::Serde(err) => tracing::error!("Failed (de)serializing audit event {err:?}"),
+            Error::Cursor => return http::StatusCode::BAD_REQUEST,
+        }
+
+        http::StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Sled(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Serde(err)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Signup,
+    Signin,
+    Signout,
+    SessionExpired,
+    PasswordChanged,
+    PasswordReset,
+    AccountDeleted,
+    TotpEnabled,
+    TotpDisabled,
+    ApiKeyCreated,
+    ApiKeyRevoked,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    Failure,
+    // El throttle rechazo el intento sin revisar la contraseña
+    Locked,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Event {
+    pub ts: Timestamp,
+    pub kind: Kind,
+    pub outcome: Outcome,
+    pub username: Option<String>,
+    // Lo que se escribio al iniciar sesion cuando no corresponde a ningun
+    // usuario
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identifier: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl Event {
+    pub fn new(kind: Kind, outcome: Outcome, username: &str, client: &ClientInfo) -> Self {
+        Event {
+            ts: Timestamp::now(),
+            kind,
+            outcome,
+            username: Some(username.to_string()),
+            identifier: None,
+            ip: client.ip,
+            user_agent: client.user_agent.as_deref().map(truncate),
+        }
+    }
+
+    pub fn unknown_user(
+        kind: Kind,
+        outcome: Outcome,
+        identifier: &str,
+        client: &ClientInfo,
+    ) -> Self {
+        Event {
+            username: None,
+            identifier: Some(truncate(identifier)),
+            ..Event::new(kind, outcome, "", client)
+        }
+    }
+}
+
+fn truncate(value: &str) -> String {
+    value.chars().take(MAX_FIELD_LENGTH).collect()
+}
+
+// Solo se agregan eventos, no hay forma de editarlos ni borrarlos desde la API.
+// Lo unico que los borra es la purga de una cuenta
+pub struct AuditLog {
+    trees: Mutex<AuditTrees>,
+}
+
+struct AuditTrees {
+    events: sled::Tree<1024>,
+    by_user: sled::Tree<1024>,
+    // Ultimo `Locked` guardado por IP, solo vive en memoria
+    last_locked: HashMap<Option<IpAddr>, Timestamp>,
+}
+
+impl AuditLog {
+    pub fn new(db: &sled::Db<1024>) -> std::io::Result<Self> {
+        Ok(AuditLog {
+            trees: Mutex::new(AuditTrees {
+                events: db.open_tree("audit_log")?,
+                by_user: db.open_tree("audit_log_by_user")?,
+                last_locked: HashMap::new(),
+            }),
+        })
+    }
+
+    pub async fn record(&self, event: Event) -> Result<(), Error> {
+        let mut trees = self.trees.lock().await;
+
+        // Sin esto una sola IP haria crecer el log sin limite reintentando
+        // contra una cuenta bloqueada
+        if event.outcome == Outcome::Locked {
+            trees
+                .last_locked
+                .retain(|_, last| last.duration_until(event.ts) < LOCKED_INTERVAL);
+
+            if trees.last_locked.contains_key(&event.ip) {
+                return Ok(());
+            }
+            trees.last_locked.insert(event.ip, event.ts);
+        }
+
+        let key = event_key(event.ts);
+
+        trees.events.insert(&key, serde_json::to_vec(&event)?)?;
+        if let Some(username) = &event.username {
+            trees
+                .by_user
+                .insert(user_index_key(username, &key), key.as_slice())?;
+        }
+
+        Ok(())
+    }
+
+    // Los mas recientes primero. Se lee por tandas de `SCAN_BATCH` llaves y el
+    // lock solo se toma durante cada una, `record` corre en cada login y no
+    // debe esperar a que termine una consulta larga
+    pub async fn list(&self, filter: &Filter) -> Result<EventsResponse, Error> {
+        let limit = filter.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+        let prefix = filter.username.as_deref().map(user_index_prefix);
+
+        // Llave del ultimo evento visto, la siguiente tanda empieza antes de ella
+        let mut before = match &filter.cursor {
+            Some(cursor) => Some(decode_cursor(cursor).ok_or(Error::Cursor)?),
+            None => None,
+        };
+
+        let mut events = vec![];
+        loop {
+            let trees = self.trees.lock().await;
+
+            let keys = match (&prefix, &before) {
+                (Some(prefix), before) => {
+                    let end = match before {
+                        Some(key) => [prefix.as_slice(), key.as_slice()].concat(),
+                        None => prefix_end(prefix),
+                    };
+
+                    trees
+                        .by_user
+                        .range(prefix.as_slice()..end.as_slice())
+                        .rev()
+                        .take(SCAN_BATCH)
+                        .map(|entry| entry.map(|(_, key)| key))
+                        .collect::<std::io::Result<Vec<_>>>()?
+                }
+                (None, Some(before)) => trees
+                    .events
+                    .range(..before.as_slice())
+                    .rev()
+                    .take(SCAN_BATCH)
+                    .map(|entry| entry.map(|(key, _)| key))
+                    .collect::<std::io::Result<Vec<_>>>()?,
+                (None, None) => trees
+                    .events
+                    .iter()
+                    .rev()
+                    .take(SCAN_BATCH)
+                    .map(|entry| entry.map(|(key, _)| key))
+                    .collect::<std::io::Result<Vec<_>>>()?,
+            };
+
+            let mut batch = Vec::with_capacity(keys.len());
+            for key in &keys {
+                batch.push((key.to_vec(), trees.events.get(key)?));
+            }
+            drop(trees);
+
+            for (key, bytes) in batch {
+                before = Some(key);
+                let Some(bytes) = bytes else {
+                    continue;
+                };
+                let event: Event = serde_json::from_slice(&bytes)?;
+
+                // Vamos hacia atras en el tiempo, de aqui en adelante todo es mas viejo
+                if filter.since.is_some_and(|since| event.ts < since) {
+                    return Ok(EventsResponse {
+                        events,
+                        next_cursor: None,
+                    });
+                }
+
+                if filter.until.is_some_and(|until| event.ts > until)
+                    || filter.kind.is_some_and(|kind| kind != event.kind)
+                    || filter
+                        .outcome
+                        .is_some_and(|outcome| outcome != event.outcome)
+                {
+                    continue;
+                }
+
+                events.push(event);
+                if events.len() >= limit {
+                    return Ok(EventsResponse {
+                        events,
+                        next_cursor: before.as_deref().map(encode_cursor),
+                    });
+                }
+            }
+
+            if keys.len() < SCAN_BATCH {
+                return Ok(EventsResponse {
+                    events,
+                    next_cursor: None,
+                });
+            }
+        }
+    }
+
+    // Todos los eventos de `username` para el export, pagina por pagina
+    pub async fn list_user(&self, username: &str) -> Result<Vec<Event>, Error> {
+        let mut filter = Filter {
+            username: Some(username.to_string()),
+            limit: Some(MAX_LIMIT),
+            ..Filter::default()
+        };
+
+        let mut events = vec![];
+        loop {
+            let page = self.list(&filter).await?;
+            events.extend(page.events);
+
+            match page.next_cursor {
+                Some(cursor) => filter.cursor = Some(cursor),
+                None => return Ok(events),
+            }
+        }
+    }
+
+    // Borra por tandas para no dejar esperando a `record` mientras tanto,
+    // regresa cuantos eventos se borraron
+    pub async fn forget(&self, username: &str) -> Result<usize, Error> {
+        let prefix = user_index_prefix(username);
+        let mut removed = 0;
+
+        loop {
+            let trees = self.trees.lock().await;
+
+            let entries = trees
+                .by_user
+                .scan_prefix(&prefix)
+                .take(SCAN_BATCH)
+                .collect::<std::io::Result<Vec<_>>>()?;
+
+            for (index_key, event_key) in &entries {
+                trees.events.remove(event_key)?;
+                trees.by_user.remove(index_key)?;
+            }
+            removed += entries.len();
+
+            if entries.len() < SCAN_BATCH {
+                return Ok(removed);
+            }
+        }
+    }
+}
+
+// Timestamp en big endian para que el orden de las llaves sea el cronologico,
+// con bytes aleatorios al final por si dos eventos caen en el mismo nanosegundo
+fn event_key(ts: Timestamp) -> Vec<u8> {
+    let mut key = ts.as_nanosecond().to_be_bytes().to_vec();
+    key.extend_from_slice(&rand::random::<u32>().to_be_bytes());
+    key
+}
+
+fn user_index_prefix(username: &str) -> Vec<u8> {
+    let mut prefix = auth::lookup_key(username).into_bytes();
+    prefix.push(0);
+    prefix
+}
+
+fn user_index_key(username: &str, event_key: &[u8]) -> Vec<u8> {
+    let mut key = user_index_prefix(username);
+    key.extend_from_slice(event_key);
+    key
+}
+
+// La primera llave despues de todas las que empiezan con `prefix`, que siempre
+// termina en 0
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    if let Some(last) = end.last_mut() {
+        *last += 1;
+    }
+    end
+}
+
+// El cursor es la llave del ultimo evento regresado, en hex
+fn encode_cursor(key: &[u8]) -> String {
+    key.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_cursor(cursor: &str) -> Option<Vec<u8>> {
+    if cursor.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..cursor.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(cursor.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+#[derive(Deserialize, Default)]
+pub struct Filter {
+    username: Option<String>,
+    kind: Option<Kind>,
+    outcome: Option<Outcome>,
+    since: Option<Timestamp>,
+    until: Option<Timestamp>,
+    limit: Option<usize>,
+    // El `next_cursor` de la pagina anterior
+    cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct EventsResponse {
+    events: Vec<Event>,
+    // `None` cuando ya no hay mas eventos que cumplan el filtro
+    next_cursor: Option<String>,
+}
+
+pub async fn get_security_events(
+    State(ctx): State<Ctx>,
+    session: Session,
+    Query(filter): Query<Filter>,
+) -> Result<axum::Json<EventsResponse>, Response> {
+    let filter = Filter {
+        username: Some(session.username),
+        ..filter
+    };
+
+    let events = ctx
+        .audit
+        .list(&filter)
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    Ok(axum::Json(events))
+}
+
+pub async fn query_security_events(
+    State(ctx): State<Ctx>,
+    admin: auth::RequireRole<auth::Admin>,
+    Query(filter): Query<Filter>,
+) -> Result<axum::Json<EventsResponse>, Response> {
+    tracing::info!(
+        "{admin} queried the audit log for {username:?}",
+        admin = admin.session.username,
+        username = filter.username
+    );
+
+    let events = ctx
+        .audit
+        .list(&filter)
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    Ok(axum::Json(events))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(username: Option<&str>, limit: usize, cursor: Option<String>) -> Filter {
+        Filter {
+            username: username.map(str::to_string),
+            kind: None,
+            outcome: None,
+            since: None,
+            until: None,
+            limit: Some(limit),
+            cursor,
+        }
+    }
+
+    #[tokio::test]
+    async fn list_pages_through_every_event_with_the_cursor() {
+        let dir = tempfile::tempdir().expect("temporary directory");
+        let db = sled::open(dir.path()).expect("temporary sled db");
+        let audit = AuditLog::new(&db).expect("audit trees");
+        let client = ClientInfo {
+            ip: None,
+            user_agent: None,
+        };
+
+        for username in ["maria", "Maria", "pedro", "maria", "pedro"] {
+            let event = Event::new(Kind::Signin, Outcome::Success, username, &client);
+            audit.record(event).await.unwrap();
+        }
+
+        for (username, expected) in [(Some("MARIA"), 3), (None, 5)] {
+            let mut seen = vec![];
+            let mut cursor = None;
+
+            loop {
+                let page = audit.list(&filter(username, 2, cursor)).await.unwrap();
+                assert!(page.events.len() <= 2);
+                seen.extend(page.events.into_iter().map(|event| event.ts));
+
+                cursor = page.next_cursor;
+                if cursor.is_none() {
+                    break;
+                }
+            }
+
+            assert_eq!(seen.len(), expected);
+            assert!(seen.is_sorted_by(|newer, older| newer >= older));
+        }
+    }
+
+    #[tokio::test]
+    async fn list_rejects_a_malformed_cursor() {
+        let dir = tempfile::tempdir().expect("temporary directory");
+        let db = sled::open(dir.path()).expect("temporary sled db");
+        let audit = AuditLog::new(&db).expect("audit trees");
+
+        for cursor in ["xyz", "abc"] {
+            let listed = audit.list(&filter(None, 2, Some(cursor.to_string()))).await;
+            assert!(matches!(listed, Err(Error::Cursor)));
+        }
+    }
+}
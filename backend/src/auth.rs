@@ -1,38 +1,141 @@
-use std::ops::Not;
+use std::{collections::HashMap, marker::PhantomData, net::SocketAddr, ops::Not};
 
 use axum::{
     RequestExt, Router,
     body::Bytes,
-    extract::{FromRequestParts, OptionalFromRequestParts, Request, State},
+    extract::{ConnectInfo, FromRequestParts, OptionalFromRequestParts, Path, Request, State},
     http,
     middleware::Next,
     response::{IntoResponse, Response},
 };
 use facet::Facet;
 
-use crate::{Ctx, json::Json, neo4j};
+use crate::{
+    Ctx, account,
+    api_key::{self, ApiKey},
+    args::UnverifiedPolicy,
+    audit, cookie,
+    json::Json,
+    mail, neo4j, oidc, password, throttle, totp,
+};
 
+// `identifier` puede ser el username o el correo
 #[derive(Facet, Clone, Copy)]
 struct SigninReq<'inp> {
-    username: &'inp str,
+    identifier: &'inp str,
     password: &'inp str,
 }
 
+#[derive(Facet, Clone, Copy)]
+struct RefreshReq<'inp> {
+    refresh_token: &'inp str,
+}
+
 #[derive(Facet, serde::Serialize)]
-struct Token {
-    token: String,
+pub struct Token {
+    pub token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+}
+
+// Lo que recibe el frontend en modo cookies, los tokens no salen de las cookies
+#[derive(serde::Serialize)]
+pub struct CookieToken {
+    pub csrf_token: String,
+    pub expires_in: i64,
+}
+
+pub fn token_response(ctx: &Ctx, token: Token) -> Response {
+    if ctx.auth.cookie_sessions.not() {
+        return axum::Json(token).into_response();
+    }
+
+    let csrf_token = generate_random_token::<32>();
+
+    (
+        cookie::session_cookies(ctx, &token, &csrf_token),
+        axum::Json(CookieToken {
+            csrf_token,
+            expires_in: token.expires_in,
+        }),
+    )
+        .into_response()
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct Session {
-    token: String,
+    #[serde(default)]
+    pub id: String,
+    #[serde(alias = "token")]
+    pub token_hash: String,
     #[serde(alias = "user")]
     pub username: String,
     created_on: jiff::Timestamp,
     duration: jiff::SignedDuration,
+    #[serde(default)]
+    user_agent: Option<String>,
+    #[serde(default)]
+    ip: Option<std::net::IpAddr>,
+    #[serde(default)]
+    pub last_seen: Option<jiff::Timestamp>,
+    #[serde(default)]
+    token_expires_on: Option<jiff::Timestamp>,
+    #[serde(default, alias = "refresh_token")]
+    pub refresh_token_hash: Option<String>,
+}
+
+// Cada refresh token emitido apunta a la sesion (familia) que lo creo. Solo el
+// que guarda la sesion es valido, cualquier otro es un reuso
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct RefreshToken {
+    pub username: String,
+    pub session: String,
+}
+
+pub struct ClientInfo {
+    pub ip: Option<std::net::IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientInfo {
+    type Rejection = std::convert::Infallible;
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        let user_agent = parts
+            .headers
+            .get(http::header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+
+        Ok(ClientInfo { ip, user_agent })
+    }
 }
 
 pub async fn protect_routes(state: State<Ctx>, mut req: Request, next: Next) -> Response {
+    // Las API keys solo valen detras de este middleware, dejamos su sesion en
+    // las extensiones para que la encuentre el extractor de `Session`
+    match req
+        .extract_parts_with_state::<Option<ApiKey>, _>(&state)
+        .await
+    {
+        Err(code) => return code.into_response(),
+        Ok(Some(api_key)) => {
+            if api_key.allows(req.method(), req.uri().path()).not() {
+                return http::StatusCode::FORBIDDEN.into_response();
+            }
+
+            req.extensions_mut().insert(api_key.into_session());
+            return next.run(req).await;
+        }
+        Ok(None) => {}
+    }
+
     let session_extract_res = req
         .extract_parts_with_state::<Option<Session>, _>(&state)
         .await;
@@ -57,47 +160,207 @@ impl FromRequestParts<Ctx> for Session {
         parts: &mut http::request::Parts,
         state: &Ctx,
     ) -> Result<Self, Self::Rejection> {
-        let axum_auth::AuthBearer(auth) = axum_auth::AuthBearer::from_request_parts(parts, state)
-            .await
-            .map_err(|_err| http::StatusCode::UNAUTHORIZED)?;
-        let guard = state.sled_tree.lock().await;
-
-        let session = {
-            guard.get(&auth).map_err(|err| {
-                tracing::error!("Failed reading key from sled tree {err:?}");
-                http::StatusCode::INTERNAL_SERVER_ERROR
-            })?
+        if let Some(session) = parts.extensions.get::<Session>() {
+            return Ok(session.clone());
+        }
+
+        let auth = match axum_auth::AuthBearer::from_request_parts(parts, state).await {
+            Ok(axum_auth::AuthBearer(auth)) => auth,
+            Err(_err) if state.auth.cookie_sessions => {
+                let Some(auth) = cookie::read(&parts.headers, cookie::SESSION) else {
+                    Err(http::StatusCode::UNAUTHORIZED)?
+                };
+
+                // El navegador manda la cookie solo, lo que cambia estado tiene
+                // que traer tambien el CSRF token
+                if parts.method.is_safe().not() && cookie::check_csrf(&parts.headers).not() {
+                    Err(http::StatusCode::FORBIDDEN)?
+                }
+
+                auth.to_string()
+            }
+            Err(_err) => Err(http::StatusCode::UNAUTHORIZED)?,
         };
 
-        let session: Session = match session {
-            Some(info) => serde_json::from_slice(&info).map_err(|err| {
-                tracing::error!("Failed deserializing Session {err:?}");
-                http::StatusCode::INTERNAL_SERVER_ERROR
-            }),
-            None => Err(http::StatusCode::UNAUTHORIZED),
-        }?;
-
-        if jiff::Timestamp::now()
-            .duration_until(
-                session
-                    .created_on
-                    .checked_add(session.duration)
-                    .expect("in overflows we do not believe"),
-            )
-            .is_negative()
-        {
-            guard.remove(&auth).map_err(|err| {
-                tracing::error!("Failed reading key from sled tree {err:?}");
-                http::StatusCode::INTERNAL_SERVER_ERROR
-            })?;
+        let Some(mut session) = state.sessions.get(&hash_token(state, &auth)).await? else {
+            Err(http::StatusCode::UNAUTHORIZED)?
+        };
+
+        if session.is_expired() {
+            state
+                .sessions
+                .remove(&session.username, &session.id)
+                .await?;
+
+            let Ok(client) = ClientInfo::from_request_parts(parts, state).await;
+            state
+                .audit
+                .record(audit::Event::new(
+                    audit::Kind::SessionExpired,
+                    audit::Outcome::Failure,
+                    &session.username,
+                    &client,
+                ))
+                .await?;
+
+            Err(http::StatusCode::UNAUTHORIZED)?
+        }
 
+        let now = jiff::Timestamp::now();
+        if session
+            .token_expires_on
+            .is_some_and(|expires_on| expires_on < now)
+        {
             Err(http::StatusCode::UNAUTHORIZED)?
+        }
+
+        if session
+            .last_seen
+            .is_none_or(|last_seen| last_seen.duration_until(now) > LAST_SEEN_RESOLUTION)
+        {
+            state
+                .sessions
+                .touch(&session.username, &session.id, &session.token_hash, now)
+                .await?;
+            session.last_seen = Some(now);
+        }
+
+        Ok(session)
+    }
+}
+
+// Sesion de un usuario con el correo verificado, o de cualquiera si la
+// politica lo permite
+pub struct Verified(pub Session);
+
+impl FromRequestParts<Ctx> for Verified {
+    type Rejection = http::StatusCode;
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &Ctx,
+    ) -> Result<Self, Self::Rejection> {
+        let session = <Session as FromRequestParts<Ctx>>::from_request_parts(parts, state).await?;
+
+        if matches!(state.auth.unverified_policy, UnverifiedPolicy::Allow)
+            || is_verified(state, &session.username).await?
+        {
+            Ok(Verified(session))
+        } else {
+            Err(http::StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+async fn is_verified(ctx: &Ctx, username: &str) -> Result<bool, http::StatusCode> {
+    // Los usuarios creados antes de la verificacion no tienen la propiedad
+    let mut stream = ctx
+        .neo4j
+        .execute_read(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                }) RETURN coalesce(u.verified, true) AS verified"#,
+            ))
+            .param("username_key", lookup_key(username)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)?;
+
+    Ok(row.is_some_and(|row| row.get::<bool>("verified").unwrap_or_default()))
+}
+
+pub trait Role {
+    const NAME: &'static str;
+}
+
+pub struct Admin;
+
+impl Role for Admin {
+    const NAME: &'static str = "admin";
+}
+
+// Sesion de un usuario que tiene el rol `R` en `User.roles`
+pub struct RequireRole<R: Role> {
+    pub session: Session,
+    role: PhantomData<R>,
+}
+
+impl<R: Role + Send + Sync> FromRequestParts<Ctx> for RequireRole<R> {
+    type Rejection = http::StatusCode;
+    async fn from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &Ctx,
+    ) -> Result<Self, Self::Rejection> {
+        let session = <Session as FromRequestParts<Ctx>>::from_request_parts(parts, state).await?;
+
+        if has_role(state, &session.username, R::NAME).await? {
+            Ok(RequireRole {
+                session,
+                role: PhantomData,
+            })
         } else {
-            Ok(session)
+            Err(http::StatusCode::FORBIDDEN)
         }
     }
 }
 
+async fn has_role(ctx: &Ctx, username: &str, role: &str) -> Result<bool, http::StatusCode> {
+    let mut stream = ctx
+        .neo4j
+        .execute_read(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                }) RETURN $role IN coalesce(u.roles, []) AS has_role"#,
+            ))
+            .param("username_key", lookup_key(username))
+            .param("role", role),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)?;
+
+    Ok(row.is_some_and(|row| row.get::<bool>("has_role").unwrap_or_default()))
+}
+
+// Regresa `false` si el usuario no existe
+pub async fn grant_role<R: Role>(
+    graph: &neo4rs::Graph,
+    username: &str,
+) -> Result<bool, neo4rs::Error> {
+    let mut stream = graph
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                })
+                SET u.roles = CASE
+                    WHEN $role IN coalesce(u.roles, []) THEN u.roles
+                    ELSE coalesce(u.roles, []) + $role
+                END
+                RETURN u.username AS username"#,
+            ))
+            .param("username_key", lookup_key(username))
+            .param("role", R::NAME),
+        )
+        .await?;
+
+    Ok(stream.next().await?.is_some())
+}
+
 impl OptionalFromRequestParts<Ctx> for Session {
     type Rejection = http::StatusCode;
     async fn from_request_parts(
@@ -118,129 +381,1245 @@ impl OptionalFromRequestParts<Ctx> for Session {
 pub fn router() -> Router<Ctx> {
     Router::new()
         .route("/signin", axum::routing::post(login_user))
+        .route("/signin/totp", axum::routing::post(totp::signin_totp))
         .route("/signup", axum::routing::post(register_user))
+        .route("/refresh", axum::routing::post(refresh_session))
+        .route("/forgot", axum::routing::post(forgot_password))
+        .route("/reset", axum::routing::post(reset_password))
+        .route("/verify/{token}", axum::routing::get(verify_mail))
+        .route("/verify/resend", axum::routing::post(resend_verification))
+        .route("/restore", axum::routing::post(account::restore_account))
+        .route("/oidc/{provider}/start", axum::routing::get(oidc::start))
+        .route(
+            "/oidc/{provider}/callback",
+            axum::routing::get(oidc::callback),
+        )
+        .route("/signout", axum::routing::post(logout_user))
+        .route("/signout/all", axum::routing::post(logout_user_everywhere))
 }
 
 impl Session {
-    fn to_bytes(&self) -> Vec<u8> {
-        serde_json::to_vec(&self).unwrap()
+    // Una API key no vive en el store ni expira, esta sesion nunca se guarda
+    pub fn from_api_key(
+        id: String,
+        key_hash: String,
+        username: String,
+        created_on: jiff::Timestamp,
+    ) -> Self {
+        Session {
+            id,
+            token_hash: key_hash,
+            username,
+            created_on,
+            duration: jiff::SignedDuration::ZERO,
+            user_agent: None,
+            ip: None,
+            last_seen: None,
+            token_expires_on: None,
+            refresh_token_hash: None,
+        }
+    }
+
+    pub fn expires_on(&self) -> jiff::Timestamp {
+        self.created_on
+            .checked_add(self.duration)
+            .expect("in overflows we do not believe")
+    }
+
+    // Los refresh no cambian `created_on`, es cuando se escribio la contraseña
+    // o se volvio del provider
+    pub fn signed_in_within(&self, window: jiff::SignedDuration) -> bool {
+        self.created_on.duration_until(jiff::Timestamp::now()) <= window
+    }
+
+    pub fn is_expired(&self) -> bool {
+        jiff::Timestamp::now()
+            .duration_until(self.expires_on())
+            .is_negative()
     }
 }
 
-fn generate_random_token<const LENGTH: usize>() -> String {
-    use rand::Rng;
+// No escribimos al store en cada request, solo cuando `last_seen` ya es viejo
+const LAST_SEEN_RESOLUTION: jiff::SignedDuration = jiff::SignedDuration::from_secs(60);
 
-    rand::rng()
-        .sample_iter(&rand::distr::Alphanumeric)
-        .take(LENGTH)
-        .map(char::from)
+// Llave con la que se buscan usernames y correos, asi `Maria`, `maria` y
+// `ｍａｒｉａ` son el mismo usuario
+pub fn lookup_key(value: &str) -> String {
+    use unicode_normalization::UnicodeNormalization;
+
+    value.trim().nfkc().collect::<String>().to_lowercase()
+}
+
+// Calcula `username_key` y `mail_key` de los usuarios que todavia no los
+// tienen. Cada llave se pone por separado, la que choca con la de otro usuario
+// se deja vacia y la cuenta queda en `unresolved` para resolverla a mano
+pub struct Backfill {
+    pub updated: usize,
+    pub unresolved: Vec<String>,
+}
+
+pub async fn backfill_lookup_keys(graph: &neo4rs::Graph) -> Result<Backfill, neo4rs::Error> {
+    let mut stream = graph
+        .execute(neo4rs::Query::new(String::from(
+            r#"MATCH (u:User)
+            RETURN elementId(u) AS id,
+                u.username AS username,
+                u.mail AS mail,
+                u.username_key IS NULL OR u.mail_key IS NULL AS missing"#,
+        )))
+        .await?;
+
+    let mut users = Vec::new();
+    let mut usernames = HashMap::<String, Vec<String>>::new();
+    let mut mails = HashMap::<String, Vec<String>>::new();
+
+    while let Some(row) = stream.next().await? {
+        let id: String = row.get("id").unwrap_or_default();
+        let username: String = row.get("username").unwrap_or_default();
+        let mail: Option<String> = row.get("mail").ok();
+        let missing: bool = row.get("missing").unwrap_or_default();
+
+        usernames
+            .entry(lookup_key(&username))
+            .or_default()
+            .push(username.clone());
+        if let Some(mail) = &mail {
+            mails
+                .entry(lookup_key(mail))
+                .or_default()
+                .push(username.clone());
+        }
+
+        if missing {
+            users.push((id, username, mail));
+        }
+    }
+
+    for (key, owners) in usernames.iter().chain(&mails) {
+        if owners.len() > 1 {
+            tracing::error!("Lookup key {key:?} is shared by users {owners:?}");
+        }
+    }
+
+    let unique = |keys: &HashMap<String, Vec<String>>, key: String| {
+        keys.get(&key)
+            .is_some_and(|owners| owners.len() > 1)
+            .not()
+            .then_some(key)
+    };
+
+    let mut backfill = Backfill {
+        updated: 0,
+        unresolved: Vec::new(),
+    };
+    for (id, username, mail) in users {
+        let username_key = unique(&usernames, lookup_key(&username));
+        let mail_key = mail.as_deref().map(|mail| unique(&mails, lookup_key(mail)));
+
+        // Un correo que choca cuenta como pendiente, uno que no existe no
+        if username_key.is_none() || mail_key.as_ref().is_some_and(Option::is_none) {
+            backfill.unresolved.push(username);
+        }
+
+        // `coalesce` no pisa la llave que el usuario ya tenga
+        graph
+            .run(
+                neo4rs::Query::new(String::from(
+                    r#"MATCH (u:User)
+                    WHERE elementId(u) = $id
+                    SET u.username_key = coalesce(u.username_key, $username_key),
+                        u.mail_key = coalesce(u.mail_key, $mail_key)"#,
+                ))
+                .param("id", id)
+                .param("username_key", username_key)
+                .param("mail_key", mail_key.flatten()),
+            )
+            .await?;
+
+        backfill.updated += 1;
+    }
+
+    Ok(backfill)
+}
+
+// Los stores nunca ven un token en claro, solo su HMAC con la llave del server
+pub fn hash_token(ctx: &Ctx, token: &str) -> String {
+    use hmac::Mac;
+
+    let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(ctx.auth.token_hash_key.as_bytes())
+        .expect("hmac accepts keys of any size");
+    mac.update(token.as_bytes());
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
         .collect()
 }
 
-async fn login_user(State(ctx): State<Ctx>, bytes: Bytes) -> Result<axum::Json<Token>, Response> {
-    let json @ Json(user): Json<SigninReq> =
-        Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+pub async fn issue_session(
+    ctx: &Ctx,
+    username: &str,
+    client: ClientInfo,
+) -> Result<Token, http::StatusCode> {
+    let now = jiff::Timestamp::now();
+    let token = generate_random_token::<50>();
+    let refresh_token = generate_random_token::<50>();
+    let session = Session {
+        id: generate_random_token::<16>(),
+        token_hash: hash_token(ctx, &token),
+        username: username.to_string(),
+        created_on: now,
+        duration: ctx.auth.session_ttl,
+        user_agent: client.user_agent,
+        ip: client.ip,
+        last_seen: Some(now),
+        token_expires_on: Some(
+            now.checked_add(ctx.auth.access_token_ttl)
+                .expect("in overflows we do not believe"),
+        ),
+        refresh_token_hash: Some(hash_token(ctx, &refresh_token)),
+    };
 
-    if json.is_all_str_set().not() {
-        Err((http::StatusCode::BAD_REQUEST).into_response())?;
+    ctx.sessions.insert(&session).await?;
+
+    Ok(Token {
+        token,
+        refresh_token,
+        expires_in: ctx.auth.access_token_ttl.as_secs(),
+    })
+}
+
+async fn rotate_session(ctx: &Ctx, refresh_token: &str) -> Result<Token, http::StatusCode> {
+    let refresh_token_hash = hash_token(ctx, refresh_token);
+    let Some(family) = ctx.sessions.get_refresh(&refresh_token_hash).await? else {
+        Err(http::StatusCode::UNAUTHORIZED)?
+    };
+
+    let Some(mut session) = ctx
+        .sessions
+        .get_by_id(&family.username, &family.session)
+        .await?
+    else {
+        // La familia ya fue revocada
+        ctx.sessions.remove_refresh(&refresh_token_hash).await?;
+        Err(http::StatusCode::UNAUTHORIZED)?
+    };
+
+    let reused = session.refresh_token_hash.as_deref() != Some(refresh_token_hash.as_str());
+    if reused || session.is_expired() {
+        if reused {
+            tracing::warn!(
+                "Refresh token reuse detected for {username}, revoking session {id}",
+                username = family.username,
+                id = family.session
+            );
+        }
+
+        ctx.sessions
+            .remove(&family.username, &family.session)
+            .await?;
+        ctx.sessions.remove_refresh(&refresh_token_hash).await?;
+
+        Err(http::StatusCode::UNAUTHORIZED)?
     }
 
-    let mut stream = ctx
-        .neo4j
-        .execute_read(
-            neo4rs::Query::new(String::from(
-                r#"MATCH (u:User {
-                    username: $username
-                }) RETURN u.password AS password"#,
-            ))
-            .param("username", user.username),
-        )
+    // El refresh token anterior se queda en el store para detectar reusos
+    let now = jiff::Timestamp::now();
+    let previous_token = session.token_hash.clone();
+    let token = generate_random_token::<50>();
+    let refresh_token = generate_random_token::<50>();
+    session.token_hash = hash_token(ctx, &token);
+    session.refresh_token_hash = Some(hash_token(ctx, &refresh_token));
+    session.last_seen = Some(now);
+    session.token_expires_on = Some(
+        now.checked_add(ctx.auth.access_token_ttl)
+            .expect("in overflows we do not believe"),
+    );
+
+    // Otro refresh con el mismo token gano la carrera, este cliente se queda
+    // sin sesion pero la familia sigue viva para el otro
+    if ctx.sessions.rotate(&previous_token, &session).await?.not() {
+        Err(http::StatusCode::UNAUTHORIZED)?
+    }
+
+    Ok(Token {
+        token,
+        refresh_token,
+        expires_in: ctx.auth.access_token_ttl.as_secs(),
+    })
+}
+
+async fn refresh_session(
+    State(ctx): State<Ctx>,
+    headers: http::HeaderMap,
+    bytes: Bytes,
+) -> Result<Response, Response> {
+    // Sin cuerpo en modo cookies el refresh token viene en su cookie
+    let refresh_token = if ctx.auth.cookie_sessions && bytes.is_empty() {
+        if cookie::check_csrf(&headers).not() {
+            Err(http::StatusCode::FORBIDDEN.into_response())?
+        }
+
+        let Some(refresh_token) = cookie::read(&headers, cookie::REFRESH) else {
+            Err(http::StatusCode::UNAUTHORIZED.into_response())?
+        };
+
+        refresh_token.to_string()
+    } else {
+        let json @ Json(req): Json<RefreshReq> =
+            Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+
+        if json.is_all_str_set().not() {
+            Err((http::StatusCode::BAD_REQUEST).into_response())?;
+        }
+
+        req.refresh_token.to_string()
+    };
+
+    let token = rotate_session(&ctx, &refresh_token)
+        .await
+        .map_err(|res| res.into_response())?;
+
+    Ok(token_response(&ctx, token))
+}
+
+async fn list_user_sessions(ctx: &Ctx, username: &str) -> Result<Vec<Session>, http::StatusCode> {
+    let sessions = ctx.sessions.list(username).await?;
+
+    Ok(sessions
+        .into_iter()
+        .filter(|session| session.is_expired().not())
+        .collect())
+}
+
+pub async fn revoke_user_sessions(
+    ctx: &Ctx,
+    username: &str,
+    except: Option<&str>,
+) -> Result<usize, http::StatusCode> {
+    let sessions = ctx.sessions.list(username).await?;
+
+    let mut revoked = 0;
+    for session in &sessions {
+        if except.is_some_and(|id| id == session.id) {
+            continue;
+        }
+
+        ctx.sessions.remove(username, &session.id).await?;
+        revoked += 1;
+    }
+
+    Ok(revoked)
+}
+
+async fn logout_user(
+    State(ctx): State<Ctx>,
+    client: ClientInfo,
+    session: Session,
+) -> Result<Response, Response> {
+    ctx.sessions
+        .remove(&session.username, &session.id)
         .await
-        .map_err(neo4j::Error::from)
         .map_err(http::StatusCode::from)
         .map_err(|res| res.into_response())?;
 
-    let row = stream
-        .next()
+    ctx.audit
+        .record(audit::Event::new(
+            audit::Kind::Signout,
+            audit::Outcome::Success,
+            &session.username,
+            &client,
+        ))
         .await
-        .map_err(neo4j::Error::from)
         .map_err(http::StatusCode::from)
         .map_err(|res| res.into_response())?;
 
-    if let Some(row) = row
-        && password_auth::verify_password(
-            user.password,
-            row.get::<&str>("password").unwrap_or_default(),
-        )
-        .is_ok()
-    {
-        let guard = ctx.sled_tree.lock().await;
-        let token = generate_random_token::<50>();
-
-        guard
-            .insert(
-                &token,
-                Session {
-                    token: token.clone(),
-                    username: user.username.to_string(),
-                    created_on: jiff::Timestamp::now(),
-                    duration: jiff::SignedDuration::from_hours(24 * 60),
-                }
-                .to_bytes(),
-            )
-            .unwrap();
+    Ok(signed_out(&ctx))
+}
 
-        Ok(axum::Json(Token { token }))
+fn signed_out(ctx: &Ctx) -> Response {
+    if ctx.auth.cookie_sessions {
+        (cookie::clear_cookies(ctx), http::StatusCode::NO_CONTENT).into_response()
     } else {
-        Err(http::StatusCode::UNAUTHORIZED.into_response())
+        http::StatusCode::NO_CONTENT.into_response()
     }
 }
 
-#[derive(Facet, Debug, Clone, Copy)]
-struct SignupParams<'inp> {
-    mail: &'inp str,
-    #[facet(default)]
-    first_name: Option<&'inp str>,
-    #[facet(default)]
-    last_name: Option<&'inp str>,
-    username: &'inp str,
-    password: &'inp str,
-    password2: &'inp str,
+async fn logout_user_everywhere(
+    State(ctx): State<Ctx>,
+    client: ClientInfo,
+    session: Session,
+) -> Result<Response, Response> {
+    let revoked = revoke_user_sessions(&ctx, &session.username, None)
+        .await
+        .map_err(|res| res.into_response())?;
+
+    ctx.audit
+        .record(audit::Event::new(
+            audit::Kind::Signout,
+            audit::Outcome::Success,
+            &session.username,
+            &client,
+        ))
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    tracing::debug!(
+        "Revoked {revoked} sessions for {username}",
+        username = session.username
+    );
+
+    Ok(signed_out(&ctx))
 }
 
-async fn register_user(State(ctx): State<Ctx>, bytes: Bytes) -> Result<http::StatusCode, Response> {
-    let json @ Json(user): Json<SignupParams> =
-        Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+#[derive(serde::Serialize)]
+pub struct SessionInfo {
+    id: String,
+    user_agent: Option<String>,
+    ip: Option<std::net::IpAddr>,
+    created_on: jiff::Timestamp,
+    last_seen: Option<jiff::Timestamp>,
+    current: bool,
+}
 
-    if json.is_all_str_set().not() {
-        Err((http::StatusCode::BAD_REQUEST).into_response())?;
-    }
+#[derive(serde::Serialize)]
+pub struct SessionsResponse {
+    sessions: Vec<SessionInfo>,
+}
 
-    if user.password.trim() != user.password2.trim() {
-        Err(http::StatusCode::BAD_REQUEST.into_response())?
-    }
+pub async fn session_infos(
+    ctx: &Ctx,
+    session: &Session,
+) -> Result<Vec<SessionInfo>, http::StatusCode> {
+    Ok(list_user_sessions(ctx, &session.username)
+        .await?
+        .into_iter()
+        .map(|other| SessionInfo {
+            current: other.id == session.id,
+            id: other.id,
+            user_agent: other.user_agent,
+            ip: other.ip,
+            created_on: other.created_on,
+            last_seen: other.last_seen,
+        })
+        .collect())
+}
 
-    ctx.neo4j
-        .run(
-            neo4rs::Query::new(String::from(
-                r#"CREATE (u:User {
+pub async fn get_sessions(
+    State(ctx): State<Ctx>,
+    session: Session,
+) -> Result<axum::Json<SessionsResponse>, Response> {
+    let sessions = session_infos(&ctx, &session)
+        .await
+        .map_err(|res| res.into_response())?;
+
+    Ok(axum::Json(SessionsResponse { sessions }))
+}
+
+pub async fn delete_session(
+    State(ctx): State<Ctx>,
+    session: Session,
+    Path(id): Path<String>,
+) -> Result<http::StatusCode, Response> {
+    if ctx
+        .sessions
+        .remove(&session.username, &id)
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+        .is_some()
+    {
+        Ok(http::StatusCode::NO_CONTENT)
+    } else {
+        Err(http::StatusCode::NOT_FOUND.into_response())
+    }
+}
+
+pub fn generate_random_token<const LENGTH: usize>() -> String {
+    use rand::Rng;
+
+    rand::rng()
+        .sample_iter(&rand::distr::Alphanumeric)
+        .take(LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+async fn login_user(
+    State(ctx): State<Ctx>,
+    client: ClientInfo,
+    bytes: Bytes,
+) -> Result<Response, Response> {
+    let json @ Json(user): Json<SigninReq> =
+        Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+
+    if json.is_all_str_set().not() {
+        Err((http::StatusCode::BAD_REQUEST).into_response())?;
+    }
+
+    let ip = client.ip;
+    let credentials = fetch_signin_credentials(&ctx, user.identifier).await?;
+
+    // Los fallos se cuentan contra el usuario, no contra lo que se escribio,
+    // asi alternar entre correo y username no da intentos extra
+    let throttle_key = credentials
+        .as_ref()
+        .map_or(user.identifier, |credentials| credentials.username.as_str());
+
+    let signin_event = |outcome| match &credentials {
+        Some(credentials) => {
+            audit::Event::new(audit::Kind::Signin, outcome, &credentials.username, &client)
+        }
+        None => audit::Event::unknown_user(audit::Kind::Signin, outcome, user.identifier, &client),
+    };
+
+    if let Some(remaining) = ctx
+        .throttle
+        .check(throttle_key, ip)
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+    {
+        ctx.audit
+            .record(signin_event(audit::Outcome::Locked))
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        Err(throttle::Locked(remaining).into_response())?
+    }
+
+    // Siempre verificamos contra algun hash para que un identificador que no
+    // existe tarde lo mismo que una contraseña equivocada
+    let password_hash = credentials
+        .as_ref()
+        .map_or(password::DUMMY_PASSWORD_HASH.as_str(), |credentials| {
+            credentials.password_hash.as_str()
+        });
+    let verification = password::verify(user.password, password_hash);
+
+    if let Some(credentials) = &credentials
+        && verification.is_valid()
+    {
+        // De aqui en adelante usamos el username como esta guardado
+        let username = credentials.username.as_str();
+
+        if let password::Verification::Outdated = verification {
+            upgrade_password_hash(&ctx, credentials, user.password).await;
+        }
+
+        ctx.throttle
+            .reset(username)
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        if matches!(ctx.auth.unverified_policy, UnverifiedPolicy::NoSignin)
+            && is_verified(&ctx, username)
+                .await
+                .map_err(|res| res.into_response())?
+                .not()
+        {
+            Err(http::StatusCode::FORBIDDEN.into_response())?
+        }
+
+        if let Some(challenge) = totp::start_challenge(&ctx, username).await? {
+            return Ok((http::StatusCode::ACCEPTED, axum::Json(challenge)).into_response());
+        }
+
+        let event = signin_event(audit::Outcome::Success);
+        let token = issue_session(&ctx, username, client)
+            .await
+            .map_err(|res| res.into_response())?;
+
+        ctx.audit
+            .record(event)
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        Ok(token_response(&ctx, token))
+    } else {
+        ctx.throttle
+            .record_failure(throttle_key, ip)
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        ctx.audit
+            .record(signin_event(audit::Outcome::Failure))
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        Err(http::StatusCode::UNAUTHORIZED.into_response())
+    }
+}
+
+// Busca por username o por correo en un solo query, los usernames no pueden
+// tener `@` asi que nunca hay ambiguedad con un correo
+async fn fetch_signin_credentials(
+    ctx: &Ctx,
+    identifier: &str,
+) -> Result<Option<Credentials>, Response> {
+    let mut stream = ctx
+        .neo4j
+        .execute_read(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User)
+                WHERE (u.username_key = $key OR u.mail_key = $key)
+                  AND u.deletion_due IS NULL
+                RETURN u.username AS username, u.password AS password
+                LIMIT 1"#,
+            ))
+            .param("key", lookup_key(identifier)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    Ok(row.and_then(|row| {
+        Some(Credentials {
+            username: row.get("username").ok()?,
+            password_hash: row.get("password").ok()?,
+        })
+    }))
+}
+
+// Si falla no pasa nada, se vuelve a intentar en el siguiente login
+async fn upgrade_password_hash(ctx: &Ctx, credentials: &Credentials, password: &str) {
+    // Solo reemplazamos el hash que verificamos, si la contraseña cambio
+    // mientras tanto no la pisamos
+    let upgraded = ctx
+        .neo4j
+        .run(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key,
+                    password: $previous
+                }) SET u.password = $password"#,
+            ))
+            .param("username_key", lookup_key(&credentials.username))
+            .param("previous", credentials.password_hash.as_str())
+            .param("password", password_auth::generate_hash(password)),
+        )
+        .await;
+
+    match upgraded {
+        Ok(()) => tracing::info!(
+            "Upgraded password hash for {username}",
+            username = credentials.username
+        ),
+        Err(err) => tracing::error!(
+            "Failed upgrading password hash for {username} {err:?}",
+            username = credentials.username
+        ),
+    }
+}
+
+pub struct Credentials {
+    pub username: String,
+    pub password_hash: String,
+}
+
+pub async fn fetch_credentials(ctx: &Ctx, username: &str) -> Result<Option<Credentials>, Response> {
+    let mut stream = ctx
+        .neo4j
+        .execute_read(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                })
+                WHERE u.deletion_due IS NULL
+                RETURN u.username AS username, u.password AS password"#,
+            ))
+            .param("username_key", lookup_key(username)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    Ok(row.and_then(|row| {
+        Some(Credentials {
+            username: row.get("username").ok()?,
+            password_hash: row.get("password").ok()?,
+        })
+    }))
+}
+
+// Las cuentas creadas por OIDC no tienen contraseña, a esas les pedimos
+// haber iniciado sesion hace poco
+const RECENT_SIGNIN: jiff::SignedDuration = jiff::SignedDuration::from_mins(5);
+
+// Para las acciones sensibles: la contraseña si la cuenta tiene una, si no un
+// inicio de sesion reciente
+pub async fn reauthenticate(
+    ctx: &Ctx,
+    session: &Session,
+    client: &ClientInfo,
+    password: Option<&str>,
+) -> Result<bool, Response> {
+    match fetch_credentials(ctx, &session.username).await? {
+        Some(credentials) => match password {
+            Some(password) => check_password(ctx, &credentials, client, password).await,
+            None => Ok(false),
+        },
+        None => Ok(session.signed_in_within(RECENT_SIGNIN)),
+    }
+}
+
+// Pasa por el throttle igual que `login_user`, con una sesion robada tampoco
+// se puede adivinar la contraseña
+async fn check_password(
+    ctx: &Ctx,
+    credentials: &Credentials,
+    client: &ClientInfo,
+    password: &str,
+) -> Result<bool, Response> {
+    if let Some(remaining) = ctx
+        .throttle
+        .check(&credentials.username, client.ip)
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+    {
+        Err(throttle::Locked(remaining).into_response())?
+    }
+
+    if password::verify(password, &credentials.password_hash).is_valid() {
+        ctx.throttle
+            .reset(&credentials.username)
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        return Ok(true);
+    }
+
+    ctx.throttle
+        .record_failure(&credentials.username, client.ip)
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    Ok(false)
+}
+
+#[derive(Facet, Debug, Clone, Copy)]
+struct ChangePasswordParams<'inp> {
+    password: &'inp str,
+    new_password: &'inp str,
+    new_password2: &'inp str,
+}
+
+pub async fn change_password(
+    State(ctx): State<Ctx>,
+    client: ClientInfo,
+    session: Session,
+    bytes: Bytes,
+) -> Result<http::StatusCode, Response> {
+    let json @ Json(params): Json<ChangePasswordParams> =
+        Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+
+    if json.is_all_str_set().not() {
+        Err((http::StatusCode::BAD_REQUEST).into_response())?;
+    }
+
+    if params.new_password.trim() != params.new_password2.trim() {
+        Err(http::StatusCode::BAD_REQUEST.into_response())?
+    }
+
+    password::check(&ctx.auth, params.new_password).map_err(|err| err.into_response())?;
+
+    let authorized = match fetch_credentials(&ctx, &session.username).await? {
+        Some(credentials) => check_password(&ctx, &credentials, &client, params.password).await?,
+        None => false,
+    };
+
+    if authorized.not() {
+        ctx.audit
+            .record(audit::Event::new(
+                audit::Kind::PasswordChanged,
+                audit::Outcome::Failure,
+                &session.username,
+                &client,
+            ))
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        Err(http::StatusCode::FORBIDDEN.into_response())?
+    }
+
+    ctx.neo4j
+        .run(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                }) SET u.password = $password"#,
+            ))
+            .param("username_key", lookup_key(&session.username))
+            .param(
+                "password",
+                password_auth::generate_hash(params.new_password),
+            ),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    // Las API keys se quedan: quien cambia la contraseña ya probo conocerla y
+    // puede revisarlas y revocarlas en `/me/api-keys`. El reset si las revoca
+    let revoked = revoke_user_sessions(&ctx, &session.username, Some(&session.id))
+        .await
+        .map_err(|res| res.into_response())?;
+
+    ctx.audit
+        .record(audit::Event::new(
+            audit::Kind::PasswordChanged,
+            audit::Outcome::Success,
+            &session.username,
+            &client,
+        ))
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    tracing::debug!(
+        "Password changed for {username}, revoked {revoked} other sessions",
+        username = session.username
+    );
+
+    Ok(http::StatusCode::NO_CONTENT)
+}
+
+#[derive(Facet, Clone, Copy)]
+struct ForgotReq<'inp> {
+    mail: &'inp str,
+}
+
+async fn forgot_password(
+    State(ctx): State<Ctx>,
+    bytes: Bytes,
+) -> Result<http::StatusCode, Response> {
+    let json @ Json(req): Json<ForgotReq> =
+        Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+
+    if json.is_all_str_set().not() {
+        Err((http::StatusCode::BAD_REQUEST).into_response())?;
+    }
+
+    let token = generate_random_token::<50>();
+    let expires_on = jiff::Timestamp::now()
+        .checked_add(ctx.auth.password_reset_ttl)
+        .expect("in overflows we do not believe");
+
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    mail_key: $mail_key
+                })
+                SET u.reset_token = $token_hash, u.reset_expires_on = $expires_on
+                RETURN u.username AS username, u.mail AS mail"#,
+            ))
+            .param("mail_key", lookup_key(req.mail))
+            .param("token_hash", hash_token(&ctx, &token))
+            .param("expires_on", expires_on.as_second()),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    // Siempre respondemos igual para no revelar que correos estan registrados,
+    // el correo sale en otra tarea para que tampoco lo revele la latencia
+    if let Some(row) = row {
+        let username: String = row.get("username").unwrap_or_default();
+        // Al correo registrado, no al que se escribio. Otra direccion que se
+        // normaliza igual no debe recibir el token
+        let to: String = row.get("mail").unwrap_or_default();
+
+        tokio::spawn(async move {
+            let sent = ctx
+                .mail
+                .send(mail::Mail {
+                    to: &to,
+                    subject: "Restablece tu contraseña de Orbitly",
+                    body: format!(
+                        "Hola {username},\n\n\
+                         Para elegir una nueva contraseña entra a {app_url}/reset?token={token}\n\n\
+                         Si no pediste este cambio puedes ignorar este correo.\n",
+                        app_url = ctx.auth.app_url,
+                    ),
+                })
+                .await;
+
+            if let Err(err) = sent {
+                tracing::error!("Failed sending password reset to {username} {err:?}");
+            }
+        });
+    }
+
+    Ok(http::StatusCode::ACCEPTED)
+}
+
+#[derive(Facet, Clone, Copy)]
+struct ResetReq<'inp> {
+    token: &'inp str,
+    password: &'inp str,
+    password2: &'inp str,
+}
+
+async fn reset_password(
+    State(ctx): State<Ctx>,
+    client: ClientInfo,
+    bytes: Bytes,
+) -> Result<http::StatusCode, Response> {
+    let json @ Json(req): Json<ResetReq> =
+        Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+
+    if json.is_all_str_set().not() {
+        Err((http::StatusCode::BAD_REQUEST).into_response())?;
+    }
+
+    if req.password.trim() != req.password2.trim() {
+        Err(http::StatusCode::BAD_REQUEST.into_response())?
+    }
+
+    password::check(&ctx.auth, req.password).map_err(|err| err.into_response())?;
+
+    // El token se consume aunque ya haya expirado
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    reset_token: $token_hash
+                })
+                WITH u, u.reset_expires_on >= $now AS valid
+                REMOVE u.reset_token, u.reset_expires_on
+                SET u.password = CASE WHEN valid THEN $password ELSE u.password END
+                RETURN u.username AS username, valid"#,
+            ))
+            .param("token_hash", hash_token(&ctx, req.token))
+            .param("now", jiff::Timestamp::now().as_second())
+            .param("password", password_auth::generate_hash(req.password)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let Some(row) = row else {
+        Err(http::StatusCode::UNAUTHORIZED.into_response())?
+    };
+
+    if row.get::<bool>("valid").unwrap_or_default().not() {
+        Err(http::StatusCode::UNAUTHORIZED.into_response())?
+    }
+
+    let username: String = row.get("username").unwrap_or_default();
+    let revoked = revoke_user_sessions(&ctx, &username, None)
+        .await
+        .map_err(|res| res.into_response())?;
+    let revoked_keys = api_key::revoke_user_keys(&ctx, &username)
+        .await
+        .map_err(|res| res.into_response())?;
+
+    ctx.audit
+        .record(audit::Event::new(
+            audit::Kind::PasswordReset,
+            audit::Outcome::Success,
+            &username,
+            &client,
+        ))
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    tracing::debug!(
+        "Password reset for {username}, revoked {revoked} sessions and {revoked_keys} API keys"
+    );
+
+    Ok(http::StatusCode::NO_CONTENT)
+}
+
+#[derive(Facet, Debug, Clone, Copy)]
+struct SignupParams<'inp> {
+    mail: &'inp str,
+    #[facet(default)]
+    first_name: Option<&'inp str>,
+    #[facet(default)]
+    last_name: Option<&'inp str>,
+    username: &'inp str,
+    password: &'inp str,
+    password2: &'inp str,
+}
+
+async fn register_user(
+    State(ctx): State<Ctx>,
+    client: ClientInfo,
+    bytes: Bytes,
+) -> Result<http::StatusCode, Response> {
+    let json @ Json(user): Json<SignupParams> =
+        Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+
+    if json.is_all_str_set().not() {
+        Err((http::StatusCode::BAD_REQUEST).into_response())?;
+    }
+
+    if user.password.trim() != user.password2.trim() {
+        Err(http::StatusCode::BAD_REQUEST.into_response())?
+    }
+
+    // El login acepta username o correo, un `@` los haria ambiguos
+    if user.username.contains('@') {
+        Err(http::StatusCode::BAD_REQUEST.into_response())?
+    }
+
+    password::check(&ctx.auth, user.password).map_err(|err| err.into_response())?;
+
+    let verify_token = generate_random_token::<50>();
+    let now = jiff::Timestamp::now();
+
+    ctx.neo4j
+        .run(
+            neo4rs::Query::new(String::from(
+                r#"CREATE (u:User {
                     username: $username,
+                    username_key: $username_key,
                     mail: $mail,
+                    mail_key: $mail_key,
                     password: $password,
                     first_name: $first_name,
-                    last_name: $last_name
+                    last_name: $last_name,
+                    verified: false,
+                    verify_token: $verify_token,
+                    verify_sent_on: $now,
+                    verify_expires_on: $expires_on
                 })"#,
             ))
-            .param("username", user.username)
-            .param("mail", user.mail)
+            .param("username", user.username.trim())
+            .param("username_key", lookup_key(user.username))
+            .param("mail", user.mail.trim())
+            .param("mail_key", lookup_key(user.mail))
             .param("password", password_auth::generate_hash(user.password))
             .param("first_name", user.first_name)
-            .param("last_name", user.last_name),
+            .param("last_name", user.last_name)
+            .param("verify_token", hash_token(&ctx, &verify_token))
+            .param("now", now.as_second())
+            .param("expires_on", verify_expires_on(&ctx, now).as_second()),
         )
         .await
         .map_err(neo4j::Error::from)
         .map_err(http::StatusCode::from)
         .map_err(|res| res.into_response())?;
 
+    ctx.audit
+        .record(audit::Event::new(
+            audit::Kind::Signup,
+            audit::Outcome::Success,
+            user.username.trim(),
+            &client,
+        ))
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    // El correo sale en otra tarea, un SMTP lento no debe frenar el registro
+    let username = user.username.to_string();
+    let to = user.mail.to_string();
+    tokio::spawn(async move {
+        send_verification(&ctx, &username, &to, &verify_token).await;
+    });
+
     Ok(http::StatusCode::CREATED)
 }
+
+fn verify_expires_on(ctx: &Ctx, now: jiff::Timestamp) -> jiff::Timestamp {
+    now.checked_add(ctx.auth.verify_token_ttl)
+        .expect("in overflows we do not believe")
+}
+
+// Si falla solo se registra, el usuario puede pedir otro en `/verify/resend`
+async fn send_verification(ctx: &Ctx, username: &str, to: &str, verify_token: &str) {
+    let sent = ctx
+        .mail
+        .send(mail::Mail {
+            to,
+            subject: "Verifica tu correo de Orbitly",
+            body: format!(
+                "Hola {username},\n\n\
+                 Para verificar tu correo entra a {api_url}/auth/verify/{verify_token}\n",
+                api_url = ctx.auth.api_url,
+            ),
+        })
+        .await;
+
+    if let Err(err) = sent {
+        tracing::error!("Failed sending verification mail to {username} {err:?}");
+    }
+}
+
+// Minimo entre dos correos de verificacion al mismo usuario
+const VERIFY_RESEND_INTERVAL: jiff::SignedDuration = jiff::SignedDuration::from_mins(1);
+
+#[derive(Facet, Clone, Copy)]
+struct ResendReq<'inp> {
+    mail: &'inp str,
+}
+
+async fn resend_verification(
+    State(ctx): State<Ctx>,
+    bytes: Bytes,
+) -> Result<http::StatusCode, Response> {
+    let json @ Json(req): Json<ResendReq> =
+        Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+
+    if json.is_all_str_set().not() {
+        Err((http::StatusCode::BAD_REQUEST).into_response())?;
+    }
+
+    let verify_token = generate_random_token::<50>();
+    let now = jiff::Timestamp::now();
+
+    // El token anterior deja de servir, solo vale el del ultimo correo
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    mail_key: $mail_key
+                })
+                WHERE u.verified = false
+                  AND coalesce(u.verify_sent_on, 0) <= $resend_after
+                SET u.verify_token = $token_hash,
+                    u.verify_sent_on = $now,
+                    u.verify_expires_on = $expires_on
+                RETURN u.username AS username, u.mail AS mail"#,
+            ))
+            .param("mail_key", lookup_key(req.mail))
+            .param("token_hash", hash_token(&ctx, &verify_token))
+            .param("now", now.as_second())
+            .param(
+                "resend_after",
+                now.checked_sub(VERIFY_RESEND_INTERVAL)
+                    .expect("in overflows we do not believe")
+                    .as_second(),
+            )
+            .param("expires_on", verify_expires_on(&ctx, now).as_second()),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    // Igual que en `/forgot` la respuesta no dice si el correo existe
+    if let Some(row) = row {
+        let username: String = row.get("username").unwrap_or_default();
+        let to: String = row.get("mail").unwrap_or_default();
+
+        tokio::spawn(async move {
+            send_verification(&ctx, &username, &to, &verify_token).await;
+        });
+    }
+
+    Ok(http::StatusCode::ACCEPTED)
+}
+
+async fn verify_mail(
+    State(ctx): State<Ctx>,
+    Path(token): Path<String>,
+) -> Result<axum::response::Redirect, Response> {
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    verify_token: $token_hash
+                })
+                WHERE u.verify_expires_on >= $now
+                SET u.verified = true
+                REMOVE u.verify_token, u.verify_sent_on, u.verify_expires_on
+                RETURN u.username AS username"#,
+            ))
+            .param("token_hash", hash_token(&ctx, &token))
+            .param("now", jiff::Timestamp::now().as_second()),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    if row.is_none() {
+        Err(http::StatusCode::NOT_FOUND.into_response())?
+    }
+
+    Ok(axum::response::Redirect::to(&format!(
+        "{app_url}/?verified=true",
+        app_url = ctx.auth.app_url
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_key_folds_case_width_and_spaces() {
+        assert_eq!(lookup_key("Maria"), "maria");
+        assert_eq!(lookup_key("  maria "), "maria");
+        assert_eq!(lookup_key("ｍａｒｉａ"), "maria");
+        assert_eq!(lookup_key("MARIA@Mail.COM"), "maria@mail.com");
+        assert_ne!(lookup_key("maria"), lookup_key("mario"));
+    }
+}
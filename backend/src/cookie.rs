@@ -0,0 +1,197 @@
+use std::ops::Not;
+
+use axum::{
+    http::{HeaderMap, HeaderName, HeaderValue, header},
+    response::AppendHeaders,
+};
+use sha2::Digest;
+
+use crate::{Ctx, auth::Token};
+
+pub const SESSION: &str = "orbitly_session";
+pub const REFRESH: &str = "orbitly_refresh";
+pub const CSRF: &str = "orbitly_csrf";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+pub const OIDC_STATE: &str = "orbitly_oidc_state";
+
+pub fn read<'h>(headers: &'h HeaderMap, name: &str) -> Option<&'h str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find_map(|(key, value)| (key == name).then_some(value))
+}
+
+fn build(
+    ctx: &Ctx,
+    name: &str,
+    value: &str,
+    path: &str,
+    max_age: i64,
+    http_only: bool,
+) -> HeaderValue {
+    build_with(ctx, name, value, path, max_age, http_only, "Strict")
+}
+
+fn build_with(
+    ctx: &Ctx,
+    name: &str,
+    value: &str,
+    path: &str,
+    max_age: i64,
+    http_only: bool,
+    same_site: &str,
+) -> HeaderValue {
+    let mut cookie =
+        format!("{name}={value}; Path={path}; Max-Age={max_age}; SameSite={same_site}; Secure");
+    if http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    if let Some(domain) = &ctx.auth.cookie_domain {
+        cookie.push_str(&format!("; Domain={domain}"));
+    }
+
+    HeaderValue::from_str(&cookie).expect("tokens and domains are ascii")
+}
+
+// El refresh token solo viaja a `/auth`, el CSRF token no es HttpOnly para que
+// el frontend lo pueda mandar de vuelta en `X-CSRF-Token`. Un arreglo de
+// headers normal reemplaza cada `Set-Cookie` con el siguiente, por eso
+// `AppendHeaders`
+pub fn session_cookies(
+    ctx: &Ctx,
+    token: &Token,
+    csrf_token: &str,
+) -> AppendHeaders<[(HeaderName, HeaderValue); 3]> {
+    let session_ttl = ctx.auth.session_ttl.as_secs();
+
+    AppendHeaders([
+        (
+            header::SET_COOKIE,
+            build(ctx, SESSION, &token.token, "/", token.expires_in, true),
+        ),
+        (
+            header::SET_COOKIE,
+            build(
+                ctx,
+                REFRESH,
+                &token.refresh_token,
+                "/auth",
+                session_ttl,
+                true,
+            ),
+        ),
+        (
+            header::SET_COOKIE,
+            build(ctx, CSRF, csrf_token, "/", session_ttl, false),
+        ),
+    ])
+}
+
+pub fn clear_cookies(ctx: &Ctx) -> AppendHeaders<[(HeaderName, HeaderValue); 3]> {
+    AppendHeaders([
+        (header::SET_COOKIE, build(ctx, SESSION, "", "/", 0, true)),
+        (
+            header::SET_COOKIE,
+            build(ctx, REFRESH, "", "/auth", 0, true),
+        ),
+        (header::SET_COOKIE, build(ctx, CSRF, "", "/", 0, false)),
+    ])
+}
+
+// Amarra el flujo de OIDC al navegador que lo empezo. Lax porque el provider
+// nos regresa con una navegacion desde su sitio y Strict no mandaria la cookie
+pub fn oidc_state(
+    ctx: &Ctx,
+    state: &str,
+    max_age: i64,
+) -> AppendHeaders<[(HeaderName, HeaderValue); 1]> {
+    AppendHeaders([(
+        header::SET_COOKIE,
+        build_with(ctx, OIDC_STATE, state, "/auth/oidc", max_age, true, "Lax"),
+    )])
+}
+
+pub fn clear_oidc_state(ctx: &Ctx) -> AppendHeaders<[(HeaderName, HeaderValue); 1]> {
+    AppendHeaders([(
+        header::SET_COOKIE,
+        build_with(ctx, OIDC_STATE, "", "/auth/oidc", 0, true, "Lax"),
+    )])
+}
+
+// Comparamos digests para no filtrar en cuanto tiempo difieren
+pub fn same_value(left: &str, right: &str) -> bool {
+    sha2::Sha256::digest(left.as_bytes()) == sha2::Sha256::digest(right.as_bytes())
+}
+
+// Double-submit: otro sitio puede hacer que el navegador mande la cookie pero no
+// puede leerla para ponerla en el header
+pub fn check_csrf(headers: &HeaderMap) -> bool {
+    let Some(cookie) = read(headers, CSRF).filter(|cookie| cookie.is_empty().not()) else {
+        return false;
+    };
+    let Some(header) = headers
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+
+    same_value(cookie, header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|&(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn read_finds_the_cookie_in_any_header() {
+        let headers = headers(&[
+            ("cookie", "theme=dark; orbitly_session=abc"),
+            ("cookie", "orbitly_csrf=xyz"),
+        ]);
+
+        assert_eq!(read(&headers, SESSION), Some("abc"));
+        assert_eq!(read(&headers, CSRF), Some("xyz"));
+        assert_eq!(read(&headers, REFRESH), None);
+        assert_eq!(read(&headers, "orbitly"), None);
+    }
+
+    #[test]
+    fn check_csrf_needs_a_matching_header() {
+        assert!(check_csrf(&headers(&[
+            ("cookie", "orbitly_csrf=xyz"),
+            ("x-csrf-token", "xyz"),
+        ])));
+        assert!(
+            check_csrf(&headers(&[
+                ("cookie", "orbitly_csrf=xyz"),
+                ("x-csrf-token", "abc"),
+            ]))
+            .not()
+        );
+        assert!(check_csrf(&headers(&[("cookie", "orbitly_csrf=xyz")])).not());
+        assert!(check_csrf(&headers(&[("x-csrf-token", "xyz")])).not());
+        assert!(
+            check_csrf(&headers(&[
+                ("cookie", "orbitly_csrf="),
+                ("x-csrf-token", ""),
+            ]))
+            .not()
+        );
+    }
+}
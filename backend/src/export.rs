@@ -0,0 +1,347 @@
+use std::{collections::BTreeMap, io::Write, ops::Not};
+
+use axum::{
+    extract::{Query, State},
+    http,
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    Ctx, Interests,
+    api_key::{self, KeyInfo},
+    audit,
+    auth::{self, Session, SessionInfo},
+    get_interests_impl, neo4j, privacy,
+};
+
+// Credenciales y tokens que no salen en el export aunque esten en el nodo
+const SECRET_PROPERTIES: &[&str] = &[
+    "password",
+    "verify_token",
+    "reset_token",
+    "restore_token",
+    "totp_secret",
+    "totp_pending",
+    "totp_recovery",
+    "totp_challenge",
+];
+
+#[derive(Debug)]
+enum Error {
+    Csv(csv::Error),
+    Zip(zip::result::ZipError),
+    Io(std::io::Error),
+}
+
+impl From<csv::Error> for Error {
+    fn from(value: csv::Error) -> Self {
+        Error::Csv(value)
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(value: zip::result::ZipError) -> Self {
+        Error::Zip(value)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::Io(value)
+    }
+}
+
+impl From<Error> for http::StatusCode {
+    fn from(value: Error) -> Self {
+        tracing::error!("Failed building export {value:?}");
+        http::StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+#[derive(serde::Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Zip,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ExportParams {
+    #[serde(default)]
+    format: Format,
+}
+
+#[derive(serde::Serialize)]
+pub struct Export {
+    exported_on: jiff::Timestamp,
+    profile: BTreeMap<String, serde_json::Value>,
+    interests: Interests,
+    matches: Vec<MatchedUser>,
+    matched_by: Vec<MatchedUser>,
+    sessions: Vec<SessionInfo>,
+    api_keys: Vec<KeyInfo>,
+    security_events: Vec<audit::Event>,
+}
+
+// Del otro usuario solo van los nombres, y solo si su privacidad lo permite
+#[derive(serde::Serialize)]
+struct MatchedUser {
+    username: String,
+    first_name: Option<String>,
+    last_name: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+enum MatchDirection {
+    // Los usuarios con los que hizo match
+    Outgoing,
+    // Los usuarios que hicieron match con el
+    Incoming,
+}
+
+pub async fn export_data(
+    State(ctx): State<Ctx>,
+    session: Session,
+    Query(params): Query<ExportParams>,
+) -> Result<Response, Response> {
+    let export = Export {
+        exported_on: jiff::Timestamp::now(),
+        profile: fetch_profile(&ctx, &session.username).await?,
+        interests: get_interests_impl(&ctx, &session.username, &session.username).await?,
+        matches: fetch_matches(&ctx, &session.username, MatchDirection::Outgoing).await?,
+        matched_by: fetch_matches(&ctx, &session.username, MatchDirection::Incoming).await?,
+        sessions: auth::session_infos(&ctx, &session)
+            .await
+            .map_err(|res| res.into_response())?,
+        api_keys: api_key::key_infos(&ctx, &session.username).await?,
+        security_events: ctx
+            .audit
+            .list_user(&session.username)
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?,
+    };
+
+    match params.format {
+        Format::Json => Ok(axum::Json(export).into_response()),
+        Format::Zip => {
+            let archive = build_zip(&export)
+                .map_err(http::StatusCode::from)
+                .map_err(|res| res.into_response())?;
+
+            Ok((
+                [
+                    (http::header::CONTENT_TYPE, "application/zip"),
+                    (
+                        http::header::CONTENT_DISPOSITION,
+                        "attachment; filename=\"orbitly-export.zip\"",
+                    ),
+                ],
+                archive,
+            )
+                .into_response())
+        }
+    }
+}
+
+async fn fetch_profile(
+    ctx: &Ctx,
+    username: &str,
+) -> Result<BTreeMap<String, serde_json::Value>, Response> {
+    let mut stream = ctx
+        .neo4j
+        .execute_read(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                }) RETURN properties(u) AS profile"#,
+            ))
+            .param("username_key", auth::lookup_key(username)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let Some(row) = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+    else {
+        Err(http::StatusCode::NOT_FOUND.into_response())?
+    };
+
+    let mut profile: BTreeMap<String, serde_json::Value> = row.get("profile").map_err(|err| {
+        tracing::error!("Failed deserializing profile {err}");
+        http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    profile.retain(|key, _| SECRET_PROPERTIES.contains(&key.as_str()).not());
+
+    Ok(profile)
+}
+
+// A diferencia de `/me/matches` no hace falta compatibilidad ni que el otro
+// tenga intereses, el export lleva todos los matches
+async fn fetch_matches(
+    ctx: &Ctx,
+    username: &str,
+    direction: MatchDirection,
+) -> Result<Vec<MatchedUser>, Response> {
+    let pattern = match direction {
+        MatchDirection::Outgoing => "-[:MATCHES]->",
+        MatchDirection::Incoming => "<-[:MATCHES]-",
+    };
+
+    let mut stream = ctx
+        .neo4j
+        .execute_read(
+            neo4rs::Query::new(format!(
+                r#"MATCH (u:User {{
+                    username_key: $username_key
+                }}){pattern}(m:User)
+                WHERE m.deletion_due IS NULL
+                WITH u, m, {visible} AS visible
+                RETURN m.username AS username,
+                    CASE WHEN visible THEN m.first_name END AS first_name,
+                    CASE WHEN visible THEN m.last_name END AS last_name
+                ORDER BY username"#,
+                visible = privacy::visible_to("m", "u", privacy::Section::Profile),
+            ))
+            .param("username_key", auth::lookup_key(username)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let mut matches = vec![];
+    while let Some(row) = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+    {
+        matches.push(MatchedUser {
+            username: row.get("username").unwrap_or_default(),
+            first_name: row.get("first_name").ok(),
+            last_name: row.get("last_name").ok(),
+        });
+    }
+
+    Ok(matches)
+}
+
+#[derive(serde::Serialize)]
+struct PropertyRow<'a> {
+    property: &'a str,
+    value: String,
+}
+
+// `Interest` se salta `liked_on` cuando no hay, en CSV todas las filas llevan
+// las mismas columnas
+#[derive(serde::Serialize)]
+struct InterestRow<'a> {
+    name: &'a str,
+    description: Option<&'a str>,
+    #[serde(rename = "type")]
+    kind: Option<&'a str>,
+    liked_on: Option<i64>,
+}
+
+// CSV no admite listas dentro de una fila, los scopes van separados por espacios
+#[derive(serde::Serialize)]
+struct KeyRow<'a> {
+    id: &'a str,
+    name: &'a str,
+    scopes: String,
+    created_on: jiff::Timestamp,
+    last_used_on: Option<jiff::Timestamp>,
+}
+
+#[derive(serde::Serialize)]
+struct EventRow<'a> {
+    ts: jiff::Timestamp,
+    kind: audit::Kind,
+    outcome: audit::Outcome,
+    ip: Option<std::net::IpAddr>,
+    user_agent: Option<&'a str>,
+}
+
+fn build_zip(export: &Export) -> Result<Vec<u8>, Error> {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = zip::write::SimpleFileOptions::default();
+
+    zip.start_file("profile.csv", options)?;
+    zip.write_all(&to_csv(export.profile.iter().map(|(property, value)| {
+        PropertyRow {
+            property,
+            value: match value {
+                serde_json::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            },
+        }
+    }))?)?;
+
+    zip.start_file("interests.csv", options)?;
+    zip.write_all(&to_csv(export.interests.interests.iter().map(
+        |interest| InterestRow {
+            name: &interest.name,
+            description: interest.description.as_deref(),
+            kind: interest.kind.as_deref(),
+            liked_on: interest.liked_on,
+        },
+    ))?)?;
+
+    zip.start_file("matches.csv", options)?;
+    zip.write_all(&to_csv(export.matches.iter())?)?;
+
+    zip.start_file("matched_by.csv", options)?;
+    zip.write_all(&to_csv(export.matched_by.iter())?)?;
+
+    zip.start_file("sessions.csv", options)?;
+    zip.write_all(&to_csv(export.sessions.iter())?)?;
+
+    zip.start_file("api_keys.csv", options)?;
+    zip.write_all(&to_csv(export.api_keys.iter().map(|key| {
+        KeyRow {
+            id: &key.id,
+            name: &key.name,
+            scopes: key
+                .scopes
+                .iter()
+                .map(|scope| scope.as_str())
+                .collect::<Vec<_>>()
+                .join(" "),
+            created_on: key.created_on,
+            last_used_on: key.last_used_on,
+        }
+    }))?)?;
+
+    zip.start_file("security_events.csv", options)?;
+    zip.write_all(&to_csv(export.security_events.iter().map(|event| {
+        EventRow {
+            ts: event.ts,
+            kind: event.kind,
+            outcome: event.outcome,
+            ip: event.ip,
+            user_agent: event.user_agent.as_deref(),
+        }
+    }))?)?;
+
+    Ok(zip.finish()?.into_inner())
+}
+
+fn to_csv<T: serde::Serialize>(rows: impl Iterator<Item = T>) -> Result<Vec<u8>, Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        writer.serialize(row)?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|err| Error::Io(err.into_error()))
+}
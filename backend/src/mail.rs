@@ -0,0 +1,112 @@
+use axum::http::StatusCode;
+use lettre::{AsyncTransport, Tokio1Executor, message::Mailbox};
+
+#[derive(Debug)]
+pub enum Error {
+    Address(lettre::address::AddressError),
+    Message(lettre::error::Error),
+    Smtp(lettre::transport::smtp::Error),
+    File(lettre::transport::file::Error),
+}
+
+impl From<Error> for StatusCode {
+    fn from(value: Error) -> Self {
+        tracing::error!("Failed sending mail {value:?}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl From<lettre::address::AddressError> for Error {
+    fn from(err: lettre::address::AddressError) -> Self {
+        Error::Address(err)
+    }
+}
+
+impl From<lettre::error::Error> for Error {
+    fn from(err: lettre::error::Error) -> Self {
+        Error::Message(err)
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for Error {
+    fn from(err: lettre::transport::smtp::Error) -> Self {
+        Error::Smtp(err)
+    }
+}
+
+impl From<lettre::transport::file::Error> for Error {
+    fn from(err: lettre::transport::file::Error) -> Self {
+        Error::File(err)
+    }
+}
+
+pub struct Mail<'a> {
+    pub to: &'a str,
+    pub subject: &'a str,
+    pub body: String,
+}
+
+impl Mail<'_> {
+    fn into_message(self, from: &Mailbox) -> Result<lettre::Message, Error> {
+        Ok(lettre::Message::builder()
+            .from(from.clone())
+            .to(self.to.parse()?)
+            .subject(self.subject)
+            .header(lettre::message::header::ContentType::TEXT_PLAIN)
+            .body(self.body)?)
+    }
+}
+
+#[async_trait::async_trait]
+pub trait MailSender: Send + Sync {
+    async fn send(&self, mail: Mail<'_>) -> Result<(), Error>;
+}
+
+pub struct SmtpSender {
+    from: Mailbox,
+    transport: lettre::AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpSender {
+    pub fn new(url: &str, from: Mailbox) -> Result<Self, Error> {
+        Ok(SmtpSender {
+            from,
+            transport: lettre::AsyncSmtpTransport::<Tokio1Executor>::from_url(url)?.build(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl MailSender for SmtpSender {
+    async fn send(&self, mail: Mail<'_>) -> Result<(), Error> {
+        self.transport.send(mail.into_message(&self.from)?).await?;
+        Ok(())
+    }
+}
+
+// Escribe cada correo como un `.eml` en un directorio, sirve para desarrollar
+// sin un servidor SMTP y para revisar los correos en pruebas
+pub struct OutboxSender {
+    from: Mailbox,
+    transport: lettre::AsyncFileTransport<Tokio1Executor>,
+}
+
+impl OutboxSender {
+    pub fn new(dir: &std::path::Path, from: Mailbox) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+
+        Ok(OutboxSender {
+            from,
+            transport: lettre::AsyncFileTransport::new(dir),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl MailSender for OutboxSender {
+    async fn send(&self, mail: Mail<'_>) -> Result<(), Error> {
+        let id = self.transport.send(mail.into_message(&self.from)?).await?;
+        tracing::debug!("Wrote mail {id} to outbox");
+        Ok(())
+    }
+}
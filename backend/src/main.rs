@@ -3,26 +3,54 @@ use std::{ops::Not, sync::Arc};
 use axum::{
     Router,
     body::Bytes,
-    extract::{Request, State},
+    extract::{DefaultBodyLimit, Request, State},
     http, middleware,
     response::{IntoResponse, Response},
 };
 use facet::Facet;
-use tokio::sync::Mutex;
 use tower_http::cors::{Any, CorsLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::{auth::Session, json::Json};
+use crate::{
+    audit::AuditLog,
+    auth::Session,
+    json::Json,
+    mail::{MailSender, OutboxSender, SmtpSender},
+    media::MediaStore,
+    oidc::Oidc,
+    session_store::{MemoryStore, Neo4jStore, SessionStore, SledStore},
+    throttle::LoginThrottle,
+};
 
+mod account;
+mod api_key;
 mod args;
+mod audit;
 mod auth;
+mod cookie;
+mod export;
 mod json;
+mod mail;
+mod media;
 mod neo4j;
+mod oidc;
+mod password;
+mod privacy;
+mod profile;
+mod session_store;
+mod throttle;
+mod totp;
 
 #[derive(Clone)]
 struct Ctx {
     neo4j: neo4rs::Graph,
-    sled_tree: Arc<Mutex<sled::Tree<1024>>>,
+    sessions: Arc<dyn SessionStore>,
+    mail: Arc<dyn MailSender>,
+    throttle: Arc<LoginThrottle>,
+    oidc: Arc<Oidc>,
+    audit: Arc<AuditLog>,
+    media: Arc<MediaStore>,
+    auth: Arc<args::Auth>,
 }
 
 #[tokio::main]
@@ -35,26 +63,139 @@ async fn main() -> Result<(), std::io::Error> {
 
     let args = args::Args::parse();
 
-    let ctx = Ctx {
-        neo4j: neo4rs::Graph::connect(args.neo4j.to_config().expect("correct config"))
-            .expect("failed to connect to neo4j instance"),
-        sled_tree: Arc::new(Mutex::new(
-            sled::open("/tmp/asdaksdj")
-                .expect("failed to create")
-                .open_tree("tokens")
-                .expect("as"),
-        )),
-    };
+    let neo4j = neo4rs::Graph::connect(args.neo4j.to_config().expect("correct config"))
+        .expect("failed to connect to neo4j instance");
 
     // Antes de iniciar ejecutamos todos los queries de constraint/schema/etc
-    neo4j::Migrations::run(&ctx.neo4j)
+    neo4j::Migrations::run(&neo4j)
         .await
         .expect("successful migrations");
 
-    let protected = Router::new()
+    let backfill = auth::backfill_lookup_keys(&neo4j)
+        .await
+        .expect("failed backfilling user lookup keys");
+
+    if backfill.updated > 0 {
+        tracing::info!("Backfilled lookup keys for {updated} users", updated = backfill.updated);
+    }
+
+    // Sin su llave estas cuentas no pueden iniciar sesion hasta que alguien
+    // renombre a una de las que chocan
+    for username in &backfill.unresolved {
+        tracing::error!("User {username} needs its lookup key collision resolved by hand");
+    }
+
+    if let Some(username) = args.grant_admin {
+        let granted = auth::grant_role::<auth::Admin>(&neo4j, &username)
+            .await
+            .expect("failed granting admin role");
+
+        if granted.not() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("there is no user {username}"),
+            ));
+        }
+
+        tracing::info!("Granted admin role to {username}");
+        return Ok(());
+    }
+
+    let db = sled::open(&args.sled_path).expect("failed to create");
+
+    let sessions: Arc<dyn SessionStore> = match args.session_store {
+        args::SessionBackend::Sled => {
+            Arc::new(SledStore::new(&db).expect("failed to open session trees"))
+        }
+        args::SessionBackend::Memory => Arc::new(MemoryStore::default()),
+        args::SessionBackend::Neo4j => Arc::new(Neo4jStore::new(neo4j.clone())),
+    };
+
+    let mail: Arc<dyn MailSender> = match args.mail.mail_sender {
+        args::MailBackend::Smtp => Arc::new(
+            SmtpSender::new(
+                args.mail.smtp_url.as_deref().expect("clap requires it"),
+                args.mail.mail_from,
+            )
+            .expect("invalid smtp url"),
+        ),
+        args::MailBackend::Outbox => Arc::new(
+            OutboxSender::new(&args.mail.mail_outbox, args.mail.mail_from)
+                .expect("failed to create mail outbox"),
+        ),
+    };
+
+    let throttle = LoginThrottle::new(&db, args.throttle).expect("failed to open login attempts");
+
+    let oidc = Oidc::new(args.oidc_providers);
+
+    let audit = AuditLog::new(&db).expect("failed to open audit log");
+
+    let media = MediaStore::new(args.media).expect("failed to create media directory");
+
+    if let Some(path) = &args.auth.common_passwords_file {
+        let loaded = password::load_common_passwords(path)
+            .expect("failed to read common passwords file");
+        tracing::info!("Loaded {loaded} common passwords from {path:?}");
+    }
+
+    let ctx = Ctx {
+        neo4j,
+        sessions,
+        mail,
+        throttle: Arc::new(throttle),
+        oidc: Arc::new(oidc),
+        audit: Arc::new(audit),
+        media: Arc::new(media),
+        auth: Arc::new(args.auth),
+    };
+
+    let migrated = ctx
+        .sessions
+        .migrate_plaintext_tokens(&|token: &str| auth::hash_token(&ctx, token))
+        .await
+        .expect("failed hashing stored session tokens");
+
+    if migrated > 0 {
+        tracing::info!("Hashed {migrated} plaintext session tokens");
+    }
+
+    // Sin recorder instalado los `metrics::counter!` no van a ningun lado
+    let metrics = metrics_exporter_prometheus::PrometheusBuilder::new()
+        .install_recorder()
+        .expect("failed to install metrics recorder");
+
+    tokio::spawn(session_store::sweep_expired(
+        ctx.sessions.clone(),
+        ctx.auth.session_sweep_interval.unsigned_abs(),
+    ));
+
+    if ctx.auth.account_deletion_grace.is_some() {
+        tokio::spawn(account::sweep_deleted(
+            ctx.clone(),
+            ctx.auth.session_sweep_interval.unsigned_abs(),
+        ));
+    }
+
+    // Solo los admins pueden cambiar la taxonomia compartida
+    let admin = Router::new()
         .route("/category", axum::routing::post(create_category))
-        .route("/category/search", axum::routing::post(search_category))
         .route("/genre", axum::routing::post(create_genre))
+        .route(
+            "/admin/security-events",
+            axum::routing::get(audit::query_security_events),
+        )
+        .route_layer(middleware::from_extractor_with_state::<
+            auth::RequireRole<auth::Admin>,
+            _,
+        >(ctx.clone()))
+        .layer(middleware::from_fn_with_state(
+            ctx.clone(),
+            auth::protect_routes,
+        ));
+
+    let protected = Router::new()
+        .route("/category/search", axum::routing::post(search_category))
         .route("/genre/search", axum::routing::post(search_genre))
         .route(
             "/me/interest",
@@ -73,7 +214,47 @@ async fn main() -> Result<(), std::io::Error> {
             axum::routing::get(get_contenido_recomendado),
         )
         .route("/me/shortest-path", axum::routing::post(get_shortest_path))
-        .route("/me", axum::routing::get(get_me))
+        .route(
+            "/me",
+            axum::routing::get(get_me)
+                .patch(profile::patch_me)
+                .delete(account::delete_account),
+        )
+        .route("/me/sessions", axum::routing::get(auth::get_sessions))
+        .route("/me/export", axum::routing::get(export::export_data))
+        .route(
+            "/me/security-events",
+            axum::routing::get(audit::get_security_events),
+        )
+        .route("/me/password", axum::routing::post(auth::change_password))
+        .route(
+            "/me/privacy",
+            axum::routing::get(privacy::get_privacy).put(privacy::put_privacy),
+        )
+        .route(
+            "/me/avatar",
+            axum::routing::put(profile::put_avatar).layer(DefaultBodyLimit::max(
+                // Un poco mas por los encabezados del multipart
+                ctx.media.max_upload_bytes() + 64 * 1024,
+            )),
+        )
+        .route(
+            "/me/totp",
+            axum::routing::post(totp::enroll_totp).delete(totp::disable_totp),
+        )
+        .route("/me/totp/confirm", axum::routing::post(totp::confirm_totp))
+        .route(
+            "/me/api-keys",
+            axum::routing::get(api_key::list_api_keys).post(api_key::create_api_key),
+        )
+        .route(
+            "/me/api-keys/{id}",
+            axum::routing::delete(api_key::revoke_api_key),
+        )
+        .route(
+            "/me/sessions/{id}",
+            axum::routing::delete(auth::delete_session),
+        )
         .route("/other", axum::routing::post(get_other_user))
         .route("/other/matches", axum::routing::post(get_other_user_matches))
         .route("/other/interest", axum::routing::post(get_other_user_interests))
@@ -86,19 +267,60 @@ async fn main() -> Result<(), std::io::Error> {
             auth::protect_routes,
         ));
 
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
+    // Con cookies el navegador exige un origen explicito para mandar credenciales
+    let cors = if ctx.auth.cookie_sessions {
+        CorsLayer::new()
+            .allow_origin(
+                http::HeaderValue::from_str(ctx.auth.app_url.trim_end_matches('/'))
+                    .expect("APP_URL is a valid origin"),
+            )
+            .allow_methods([
+                http::Method::GET,
+                http::Method::POST,
+                http::Method::PUT,
+                http::Method::PATCH,
+                http::Method::DELETE,
+            ])
+            .allow_headers([
+                http::header::AUTHORIZATION,
+                http::header::CONTENT_TYPE,
+                http::HeaderName::from_static(cookie::CSRF_HEADER),
+            ])
+            .allow_credentials(true)
+    } else {
+        CorsLayer::new()
+            .allow_origin(Any)
+            .allow_methods(Any)
+            .allow_headers(Any)
+    };
 
     let router = Router::new()
         .route("/ready", axum::routing::get(async || "ready"))
+        .route("/media/{hash}", axum::routing::get(media::get_media))
         .nest("/auth", auth::router())
         .merge(protected)
+        .merge(admin)
         .layer(cors)
         .layer(middleware::from_fn(log))
         .with_state(ctx);
 
+    if let Some(address) = args.metrics_address {
+        let listener = tokio::net::TcpListener::bind(address)
+            .await
+            .expect("failed to bind metrics address");
+
+        tracing::info!(
+            "Serving metrics on address {addr:?}",
+            addr = listener.local_addr()
+        );
+
+        let metrics = Router::new().route(
+            "/metrics",
+            axum::routing::get(move || std::future::ready(metrics.render())),
+        );
+        tokio::spawn(async move { axum::serve(listener, metrics).await });
+    }
+
     let listener = tokio::net::TcpListener::bind((args.address, args.port))
         .await
         .unwrap();
@@ -108,7 +330,11 @@ async fn main() -> Result<(), std::io::Error> {
         addr = listener.local_addr()
     );
 
-    axum::serve(listener, router).await
+    axum::serve(
+        listener,
+        router.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .await
 }
 
 #[derive(Facet, Debug, Clone, Copy)]
@@ -254,6 +480,7 @@ async fn search_impl<'inp>(
                 String::from(
                     r#"WITH $search AS rhs
                   MATCH (t:@LABEL)
+                  WHERE t.deletion_due IS NULL
                   WITH rhs, t,
                        apoc.text.clean(toLower(rhs)) AS normalizedCandidate,
                        apoc.text.clean(toLower(t.@CMP_FIELD)) AS normalizedExisting,
@@ -537,6 +764,10 @@ struct Interest {
     #[facet(default)]
     #[serde(rename = "type")]
     kind: Option<String>,
+    // Segundos desde epoch, los LIKES importados no lo tienen
+    #[facet(default)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    liked_on: Option<i64>,
 }
 
 #[derive(Facet, serde::Serialize, Debug, Clone)]
@@ -560,23 +791,26 @@ async fn get_user_info_impl(
     let mut stream = ctx
         .neo4j
         .execute_read(
-            neo4rs::Query::new(String::from(
+            neo4rs::Query::new(format!(
                 r#"
-                MATCH (u:User{username: $current_username})-[:LIKES]->(i1:Interest),
-                      (other:User{username: $other_username})-[:LIKES]->(i2:Interest)
+                MATCH (u:User{{username_key: $current_username}})-[:LIKES]->(i1:Interest),
+                      (other:User{{username_key: $other_username}})-[:LIKES]->(i2:Interest)
+                WHERE other.deletion_due IS NULL
                 WITH COLLECT(ID(i1)) AS u_likes, COLLECT(ID(i2)) AS other_likes, u, other
                 WITH u, other, gds.similarity.cosine(u_likes, other_likes) AS compatibility
+                WITH u, other, compatibility, {visible} AS visible
                 RETURN
                     other.username as username,
-                    other.first_name as first_name,
-                    other.last_name as last_name,
-                    other.description as description,
-                    other.avatar as avatar,
+                    CASE WHEN visible THEN other.first_name END as first_name,
+                    CASE WHEN visible THEN other.last_name END as last_name,
+                    CASE WHEN visible THEN other.description END as description,
+                    CASE WHEN visible THEN other.avatar END as avatar,
                     compatibility
                 "#,
+                visible = privacy::visible_to("other", "u", privacy::Section::Profile),
             ))
-            .param("current_username", current_username)
-            .param("other_username", target_username),
+            .param("current_username", auth::lookup_key(current_username))
+            .param("other_username", auth::lookup_key(target_username)),
         )
         .await
         .map_err(neo4j::Error::from)
@@ -605,26 +839,31 @@ async fn get_user_matches_impl(
     current_username: &str,
     target_username: &str,
 ) -> Result<Lv2Response, Response> {
+    privacy::check(ctx, current_username, target_username, privacy::Section::Matches).await?;
+
     let mut stream = ctx
         .neo4j
         .execute_read(
-            neo4rs::Query::new(String::from(
+            neo4rs::Query::new(format!(
                 r#"
-                MATCH (u:User{username: $current_username})-[:LIKES]->(i1:Interest),
-                      (other:User{username: $other_username})-[:MATCHES]->(m:User)-[:LIKES]->(i2:Interest)
+                MATCH (u:User{{username_key: $current_username}})-[:LIKES]->(i1:Interest),
+                      (other:User{{username_key: $other_username}})-[:MATCHES]->(m:User)-[:LIKES]->(i2:Interest)
+                WHERE m.deletion_due IS NULL
                 WITH COLLECT(ID(i1)) AS u_likes, COLLECT(ID(i2)) AS m_likes, u, m
                 WITH u, m, gds.similarity.cosine(u_likes, m_likes) AS compatibility
+                WITH u, m, compatibility, {visible} AS visible
                 RETURN
                     m.username as username,
-                    m.first_name as first_name,
-                    m.last_name as last_name,
-                    m.description as description,
-                    m.avatar as avatar,
+                    CASE WHEN visible THEN m.first_name END as first_name,
+                    CASE WHEN visible THEN m.last_name END as last_name,
+                    CASE WHEN visible THEN m.description END as description,
+                    CASE WHEN visible THEN m.avatar END as avatar,
                     compatibility
                 "#,
+                visible = privacy::visible_to("m", "u", privacy::Section::Profile),
             ))
-            .param("current_username", current_username)
-            .param("other_username", target_username),
+            .param("current_username", auth::lookup_key(current_username))
+            .param("other_username", auth::lookup_key(target_username)),
         )
         .await
         .map_err(neo4j::Error::from)
@@ -701,16 +940,28 @@ async fn get_other_user_matches(
     Ok(axum::Json(result))
 }
 
-async fn get_interests_impl(ctx: &Ctx, username: &str) -> Result<Interests, Response> {
+async fn get_interests_impl(
+    ctx: &Ctx,
+    current_username: &str,
+    username: &str,
+) -> Result<Interests, Response> {
+    privacy::check(ctx, current_username, username, privacy::Section::Interests).await?;
+
     let mut stream = ctx
         .neo4j
         .execute_read(
             neo4rs::Query::new(String::from(
                 r#"
-                   MATCH (:User{username: $username})-[:LIKES]->(i:Interest) RETURN i
+                   MATCH (:User{username_key: $username})-[r:LIKES]->(i:Interest)
+                   RETURN i.name AS name,
+                       i.description AS description,
+                       i.type AS type,
+                       CASE WHEN $own THEN r.created_on END AS liked_on
                 "#,
             ))
-            .param("username", username),
+            .param("username", auth::lookup_key(username))
+            // Cuando se dio el like solo lo ve el dueño
+            .param("own", auth::lookup_key(current_username) == auth::lookup_key(username)),
         )
         .await
         .map_err(neo4j::Error::from)
@@ -744,13 +995,13 @@ async fn get_interests(
     State(ctx): State<Ctx>,
     session: Session,
 ) -> Result<axum::Json<Interests>, Response> {
-    let result = get_interests_impl(&ctx, &session.username).await?;
+    let result = get_interests_impl(&ctx, &session.username, &session.username).await?;
     Ok(axum::Json(result))
 }
 
 async fn get_other_user_interests(
     State(ctx): State<Ctx>,
-    _session: Session,
+    session: Session,
     bytes: Bytes,
 ) -> Result<axum::Json<Interests>, Response> {
     let bytes = bytes.iter().as_slice();
@@ -761,7 +1012,7 @@ async fn get_other_user_interests(
         Err((http::StatusCode::BAD_REQUEST).into_response())?;
     }
 
-    let result = get_interests_impl(&ctx, params.username).await?;
+    let result = get_interests_impl(&ctx, &session.username, params.username).await?;
     Ok(axum::Json(result))
 }
 
@@ -785,29 +1036,32 @@ async fn search_users(
     let mut stream = ctx
         .neo4j
         .execute(
-            neo4rs::Query::new(String::from(
+            neo4rs::Query::new(format!(
                 r#"
-                MATCH (u:User{username: $current_username})-[:LIKES]->(i1:Interest),
+                MATCH (u:User{{username_key: $current_username}})-[:LIKES]->(i1:Interest),
                       (other:User)-[:LIKES]->(i2:Interest)
-                WHERE (toLower(other.username) CONTAINS toLower($term)
-                   OR toLower(other.first_name) CONTAINS toLower($term)
-                   OR toLower(other.last_name) CONTAINS toLower($term))
-                  AND u <> other
-                WITH COLLECT(ID(i1)) AS u_likes, COLLECT(ID(i2)) AS other_likes, u, other
-                WITH u, other, gds.similarity.cosine(u_likes, other_likes) AS compatibility
+                WHERE u <> other
+                  AND other.deletion_due IS NULL
+                WITH u, other, i1, i2, {visible} AS visible
+                WHERE toLower(other.username) CONTAINS toLower($term)
+                   OR (visible AND (toLower(other.first_name) CONTAINS toLower($term)
+                       OR toLower(other.last_name) CONTAINS toLower($term)))
+                WITH COLLECT(ID(i1)) AS u_likes, COLLECT(ID(i2)) AS other_likes, u, other, visible
+                WITH u, other, visible, gds.similarity.cosine(u_likes, other_likes) AS compatibility
                 RETURN
                     other.username as username,
-                    other.first_name as first_name,
-                    other.last_name as last_name,
-                    other.description as description,
-                    other.avatar as avatar,
+                    CASE WHEN visible THEN other.first_name END as first_name,
+                    CASE WHEN visible THEN other.last_name END as last_name,
+                    CASE WHEN visible THEN other.description END as description,
+                    CASE WHEN visible THEN other.avatar END as avatar,
                     compatibility
                 ORDER BY compatibility DESC
                 SKIP $skip
                 LIMIT $limit
                 "#,
+                visible = privacy::visible_to("other", "u", privacy::Section::Profile),
             ))
-            .param("current_username", session.username)
+            .param("current_username", auth::lookup_key(&session.username))
             .param("term", search.term)
             .param("skip", skip)
             .param("limit", page_size),
@@ -882,12 +1136,14 @@ async fn like_interest(
         .run(
             neo4rs::Query::new(String::from(
                 r#"
-                MATCH (u:User {username: $username}), (i:Interest {name: $interest_name})
-                MERGE (u)-[:LIKES]->(i)
+                MATCH (u:User {username_key: $username}), (i:Interest {name: $interest_name})
+                MERGE (u)-[r:LIKES]->(i)
+                ON CREATE SET r.created_on = $now
                 "#,
             ))
-            .param("username", session.username)
-            .param("interest_name", params.name),
+            .param("username", auth::lookup_key(&session.username))
+            .param("interest_name", params.name)
+            .param("now", jiff::Timestamp::now().as_second()),
         )
         .await
         .map_err(neo4j::Error::from)
@@ -914,11 +1170,11 @@ async fn unlike_interest(
         .run(
             neo4rs::Query::new(String::from(
                 r#"
-                MATCH (u:User {username: $username})-[r:LIKES]->(i:Interest {name: $interest_name})
+                MATCH (u:User {username_key: $username})-[r:LIKES]->(i:Interest {name: $interest_name})
                 DELETE r
                 "#,
             ))
-            .param("username", session.username)
+            .param("username", auth::lookup_key(&session.username))
             .param("interest_name", params.name),
         )
         .await
@@ -960,14 +1216,14 @@ async fn get_shortest_path(
             neo4rs::Query::new(
                 String::from(
                     r#"
-                    MATCH (u1:User{username: $username}), (u2:@LABEL{name: $target_name})
+                    MATCH (u1:User{username_key: $username}), (u2:@LABEL{name: $target_name})
                     MATCH p = shortestPath((u1)-[*..15]-(u2))
                     RETURN [n IN nodes(p) | coalesce(n.username, n.name)] AS path_nodes, length(p) AS path_length
                     "#,
                 )
                 .replace("@LABEL", params.target_label),
             )
-            .param("username", session.username)
+            .param("username", auth::lookup_key(&session.username))
             .param("target_name", params.target_name),
         )
         .await
@@ -1018,7 +1274,7 @@ async fn get_contenido_recomendado(
         .execute_read(
             neo4rs::Query::new(String::from(
                 r#"
-                MATCH (u:User{username: $username})-[:LIKES]->(:Interest)<-[:LIKES]-(a:User)-[:LIKES]->(i:Interest)
+                MATCH (u:User{username_key: $username})-[:LIKES]->(:Interest)<-[:LIKES]-(a:User)-[:LIKES]->(i:Interest)
                 WHERE NOT (u)-[:LIKES]->(i)
                 WITH DISTINCT a, i
                 RETURN i.name AS name, i.description AS description, i.type AS type, toFloat(COUNT(a)) AS score, 'collaborative' AS source
@@ -1026,7 +1282,7 @@ async fn get_contenido_recomendado(
                 LIMIT 30
                 "#,
             ))
-            .param("username", session.username.as_str()),
+            .param("username", auth::lookup_key(&session.username)),
         )
         .await
         .map_err(neo4j::Error::from)
@@ -1061,26 +1317,33 @@ async fn get_lv2_matches(
     let mut stream = ctx
         .neo4j
         .execute_read(
-            neo4rs::Query::new(String::from(
+            neo4rs::Query::new(format!(
                 r#"
-                MATCH (u:User{username: $username})-[:LIKES]->(i1:Interest),
+                MATCH (u:User{{username_key: $username}})-[:LIKES]->(i1:Interest),
                       (lv2:User)-[:LIKES]->(i2:Interest)
-                WHERE (u)-[:MATCHES]->(:User)-[:MATCHES]->(lv2)
+                WHERE EXISTS {{
+                        MATCH (u)-[:MATCHES]->(mid:User)-[:MATCHES]->(lv2)
+                        WHERE {mid_matches_visible}
+                    }}
                   AND NOT (u)-[:MATCHES]->(lv2)
                   AND u <> lv2
+                  AND lv2.deletion_due IS NULL
                 WITH COLLECT(ID(i1)) AS u_likes, COLLECT(ID(i2)) AS lv2_likes, u, lv2
                 WITH u, lv2, gds.similarity.cosine(u_likes, lv2_likes) AS compatibility
+                WITH u, lv2, compatibility, {visible} AS visible
                 RETURN
                     lv2.username as username,
-                    lv2.first_name as first_name,
-                    lv2.last_name as last_name,
-                    lv2.description as description,
-                    lv2.avatar as avatar,
+                    CASE WHEN visible THEN lv2.first_name END as first_name,
+                    CASE WHEN visible THEN lv2.last_name END as last_name,
+                    CASE WHEN visible THEN lv2.description END as description,
+                    CASE WHEN visible THEN lv2.avatar END as avatar,
                     compatibility
                 ORDER BY compatibility DESC
                 "#,
+                mid_matches_visible = privacy::visible_to("mid", "u", privacy::Section::Matches),
+                visible = privacy::visible_to("lv2", "u", privacy::Section::Profile),
             ))
-            .param("username", session.username),
+            .param("username", auth::lookup_key(&session.username)),
         )
         .await
         .map_err(neo4j::Error::from)
@@ -1112,7 +1375,7 @@ struct MatchParams<'inp> {
 
 async fn perform_match(
     State(ctx): State<Ctx>,
-    session: Session,
+    auth::Verified(session): auth::Verified,
     bytes: Bytes,
 ) -> Result<http::StatusCode, Response> {
     let bytes = bytes.iter().as_slice();
@@ -1127,12 +1390,12 @@ async fn perform_match(
         .run(
             neo4rs::Query::new(String::from(
                 r#"
-                    MATCH (u1:User { username: $username1 }), (u2:User { username: $username2 })
+                    MATCH (u1:User { username_key: $username1 }), (u2:User { username_key: $username2 })
                     MERGE (u1)-[:MATCHES]->(u2)
             "#,
             ))
-            .param("username1", session.username)
-            .param("username2", match_params.target),
+            .param("username1", auth::lookup_key(&session.username))
+            .param("username2", auth::lookup_key(match_params.target)),
         )
         .await
         .map_err(neo4j::Error::from)
@@ -1159,12 +1422,12 @@ async fn unmatch(
         .run(
             neo4rs::Query::new(String::from(
                 r#"
-                    MATCH (u1:User { username: $username1 })-[r:MATCHES]->(u2:User { username: $username2 })
+                    MATCH (u1:User { username_key: $username1 })-[r:MATCHES]->(u2:User { username_key: $username2 })
                     DELETE r
             "#,
             ))
-            .param("username1", session.username)
-            .param("username2", match_params.target),
+            .param("username1", auth::lookup_key(&session.username))
+            .param("username2", auth::lookup_key(match_params.target)),
         )
         .await
         .map_err(neo4j::Error::from)
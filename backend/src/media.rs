@@ -0,0 +1,412 @@
+use std::{io::Cursor, ops::Not, path::PathBuf};
+
+use axum::{
+    extract::{Path, State},
+    http,
+    response::{IntoResponse, Response},
+};
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, codecs::jpeg::JpegEncoder};
+use sha2::Digest;
+
+use crate::{Ctx, args};
+
+// Lado del cuadrado de cada variante del avatar
+pub const AVATAR_SIZE: u32 = 512;
+pub const THUMBNAIL_SIZE: u32 = 96;
+const JPEG_QUALITY: u8 = 85;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    Image(image::ImageError),
+    UnsupportedFormat,
+}
+
+impl From<Error> for http::StatusCode {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::Io(err) => {
+                tracing::error!("Failed accessing media store {err:?}");
+                http::StatusCode::INTERNAL_SERVER_ERROR
+            }
+            Error::Image(image::ImageError::Limits(err)) => {
+                tracing::debug!("Rejected image over the limits {err}");
+                http::StatusCode::PAYLOAD_TOO_LARGE
+            }
+            Error::Image(err) => {
+                tracing::debug!("Rejected invalid image {err}");
+                http::StatusCode::UNPROCESSABLE_ENTITY
+            }
+            Error::UnsupportedFormat => http::StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(err: image::ImageError) -> Self {
+        Error::Image(err)
+    }
+}
+
+// Los archivos se llaman por el SHA-256 de su contenido, asi el mismo archivo
+// nunca se guarda dos veces y se puede cachear para siempre
+pub struct MediaStore {
+    policy: args::Media,
+}
+
+impl MediaStore {
+    pub fn new(policy: args::Media) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&policy.media_dir)?;
+
+        Ok(MediaStore { policy })
+    }
+
+    pub fn max_upload_bytes(&self) -> usize {
+        self.policy.avatar_max_bytes
+    }
+
+    fn path(&self, hash: &str) -> PathBuf {
+        self.policy.media_dir.join(hash)
+    }
+
+    pub async fn put(&self, bytes: Vec<u8>) -> Result<String, Error> {
+        let hash = sha2::Sha256::digest(&bytes)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+
+        let path = self.path(&hash);
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(hash);
+        }
+
+        // Escribimos a un temporal y renombramos para que nadie lea un archivo
+        // a medias
+        let tmp = self.policy.media_dir.join(format!(".{hash}.tmp"));
+        tokio::fs::write(&tmp, bytes).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+
+        Ok(hash)
+    }
+
+    pub async fn get(&self, hash: &str) -> Result<Option<Vec<u8>>, Error> {
+        match tokio::fs::read(self.path(hash)).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Otro usuario pudo subir el mismo archivo, eso lo revisa quien llama
+    pub async fn remove(&self, hash: &str) -> Result<(), Error> {
+        if is_hash(hash).not() {
+            return Ok(());
+        }
+
+        match tokio::fs::remove_file(self.path(hash)).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Decodificar y volver a codificar deja fuera el EXIF y cualquier otro
+    // metadato, la orientacion se aplica antes para no perderla
+    pub fn avatar_variants(&self, bytes: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+        let mut reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format()?;
+
+        if matches!(
+            reader.format(),
+            Some(ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::WebP)
+        )
+        .not()
+        {
+            Err(Error::UnsupportedFormat)?
+        }
+
+        let mut limits = image::Limits::default();
+        limits.max_image_width = Some(self.policy.avatar_max_dimension);
+        limits.max_image_height = Some(self.policy.avatar_max_dimension);
+        reader.limits(limits);
+
+        let mut decoder = reader.into_decoder()?;
+        let orientation = decoder.orientation()?;
+        let mut image = DynamicImage::from_decoder(decoder)?;
+        image.apply_orientation(orientation);
+
+        [AVATAR_SIZE, THUMBNAIL_SIZE]
+            .into_iter()
+            .map(|size| {
+                let resized = image
+                    .resize_to_fill(size, size, image::imageops::FilterType::Lanczos3)
+                    .to_rgb8();
+
+                let mut encoded = vec![];
+                resized.write_with_encoder(JpegEncoder::new_with_quality(
+                    &mut encoded,
+                    JPEG_QUALITY,
+                ))?;
+
+                Ok((size, encoded))
+            })
+            .collect()
+    }
+}
+
+fn is_hash(value: &str) -> bool {
+    value.len() == 64
+        && value
+            .bytes()
+            .all(|byte| matches!(byte, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+pub async fn get_media(
+    State(ctx): State<Ctx>,
+    Path(hash): Path<String>,
+    headers: http::HeaderMap,
+) -> Result<Response, Response> {
+    serve(&ctx.media, &hash, &headers).await
+}
+
+async fn serve(
+    media: &MediaStore,
+    hash: &str,
+    headers: &http::HeaderMap,
+) -> Result<Response, Response> {
+    if is_hash(hash).not() {
+        Err(http::StatusCode::NOT_FOUND.into_response())?
+    }
+
+    let etag = format!("\"{hash}\"");
+    let cache_headers = [
+        (
+            http::header::CACHE_CONTROL,
+            String::from("public, max-age=31536000, immutable"),
+        ),
+        (http::header::ETAG, etag.clone()),
+    ];
+
+    // El contenido de un hash nunca cambia, si el cliente ya lo tiene le basta
+    if headers
+        .get(http::header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag))
+    {
+        return Ok((http::StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+
+    let Some(bytes) = media
+        .get(hash)
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+    else {
+        Err(http::StatusCode::NOT_FOUND.into_response())?
+    };
+
+    Ok((
+        cache_headers,
+        [(http::header::CONTENT_TYPE, "image/jpeg")],
+        bytes,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    fn store(dir: &tempfile::TempDir, avatar_max_dimension: u32) -> MediaStore {
+        MediaStore::new(args::Media {
+            media_dir: dir.path().to_path_buf(),
+            avatar_max_bytes: 1024 * 1024,
+            avatar_max_dimension,
+        })
+        .expect("media store")
+    }
+
+    // JPEG de 64x32, mitad izquierda roja y derecha azul, con un bloque EXIF
+    // que pide rotarla 180 grados
+    fn jpeg_with_exif() -> Vec<u8> {
+        let image = RgbImage::from_fn(64, 32, |x, _| {
+            if x < 32 {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        });
+
+        let mut jpeg = vec![];
+        image
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, 95))
+            .expect("encoded jpeg");
+
+        let mut exif = vec![0xFF, 0xE1, 0x00, 0x22];
+        exif.extend_from_slice(b"Exif\0\0");
+        exif.extend_from_slice(b"MM\0\x2A\0\0\0\x08");
+        exif.extend_from_slice(&[0x00, 0x01]);
+        exif.extend_from_slice(&[0x01, 0x12, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01]);
+        exif.extend_from_slice(&[0x00, 0x03, 0x00, 0x00]);
+        exif.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+
+        // El APP1 va justo despues del SOI
+        jpeg.splice(2..2, exif);
+        jpeg
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+
+    fn is_red(pixel: &Rgb<u8>) -> bool {
+        pixel[0] > 200 && pixel[2] < 60
+    }
+
+    fn is_blue(pixel: &Rgb<u8>) -> bool {
+        pixel[2] > 200 && pixel[0] < 60
+    }
+
+    #[test]
+    fn avatar_variants_reencode_without_exif() {
+        let dir = tempfile::tempdir().expect("temporary directory");
+        let media = store(&dir, 8192);
+        let original = jpeg_with_exif();
+        assert!(contains(&original, b"Exif"));
+
+        let variants = media.avatar_variants(&original).expect("variants");
+        assert_eq!(
+            variants.iter().map(|(size, _)| *size).collect::<Vec<_>>(),
+            [AVATAR_SIZE, THUMBNAIL_SIZE]
+        );
+
+        for (size, bytes) in variants {
+            assert!(contains(&bytes, b"Exif").not());
+
+            let image = ImageReader::new(Cursor::new(&bytes))
+                .with_guessed_format()
+                .expect("readable variant");
+            assert_eq!(image.format(), Some(ImageFormat::Jpeg));
+
+            let image = image.decode().expect("decoded variant").to_rgb8();
+            assert_eq!(image.dimensions(), (size, size));
+
+            // La rotacion de 180 grados deja el azul a la izquierda
+            assert!(is_blue(image.get_pixel(size / 8, size / 2)));
+            assert!(is_red(image.get_pixel(size - size / 8, size / 2)));
+        }
+    }
+
+    #[test]
+    fn avatar_variants_reject_unsupported_formats() {
+        let dir = tempfile::tempdir().expect("temporary directory");
+        let media = store(&dir, 8192);
+
+        let err = media
+            .avatar_variants(b"GIF89a\x01\x00\x01\x00\x00\x00\x00;")
+            .expect_err("gif is not accepted");
+        assert!(matches!(err, Error::UnsupportedFormat));
+
+        let err = media
+            .avatar_variants(b"not an image")
+            .expect_err("garbage is not accepted");
+        assert!(matches!(err, Error::UnsupportedFormat));
+    }
+
+    #[test]
+    fn avatar_variants_enforce_the_dimension_limit() {
+        let dir = tempfile::tempdir().expect("temporary directory");
+        let media = store(&dir, 32);
+
+        let err = media
+            .avatar_variants(&jpeg_with_exif())
+            .expect_err("wider than the limit");
+        assert!(matches!(err, Error::Image(image::ImageError::Limits(_))));
+        assert_eq!(
+            http::StatusCode::from(err),
+            http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+
+    async fn request(media: &MediaStore, hash: &str, if_none_match: Option<&str>) -> Response {
+        let mut headers = http::HeaderMap::new();
+        if let Some(value) = if_none_match {
+            headers.insert(
+                http::header::IF_NONE_MATCH,
+                http::HeaderValue::from_str(value).expect("header value"),
+            );
+        }
+
+        serve(media, hash, &headers).await.unwrap_or_else(|res| res)
+    }
+
+    async fn body(response: Response) -> Vec<u8> {
+        axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("response body")
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn serve_answers_with_cache_headers_and_etag() {
+        let dir = tempfile::tempdir().expect("temporary directory");
+        let media = store(&dir, 8192);
+        let (_, bytes) = media
+            .avatar_variants(&jpeg_with_exif())
+            .expect("variants")
+            .remove(0);
+        let hash = media.put(bytes.clone()).await.expect("stored");
+        let etag = format!("\"{hash}\"");
+
+        let response = request(&media, &hash, None).await;
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(response.headers()[http::header::ETAG], etag.as_str());
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], "image/jpeg");
+        assert!(
+            response.headers()[http::header::CACHE_CONTROL]
+                .to_str()
+                .expect("ascii header")
+                .contains("immutable")
+        );
+        assert_eq!(body(response).await, bytes);
+
+        let response = request(&media, &hash, Some("\"something-else\"")).await;
+        assert_eq!(response.status(), http::StatusCode::OK);
+        assert_eq!(body(response).await, bytes);
+    }
+
+    #[tokio::test]
+    async fn serve_answers_not_modified_when_the_etag_matches() {
+        let dir = tempfile::tempdir().expect("temporary directory");
+        let media = store(&dir, 8192);
+        let hash = media.put(jpeg_with_exif()).await.expect("stored");
+        let etag = format!("\"{hash}\"");
+
+        for if_none_match in [etag.clone(), format!("\"other\", {etag}")] {
+            let response = request(&media, &hash, Some(&if_none_match)).await;
+            assert_eq!(response.status(), http::StatusCode::NOT_MODIFIED);
+            assert_eq!(response.headers()[http::header::ETAG], etag.as_str());
+            assert!(body(response).await.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn serve_answers_not_found_for_bad_or_missing_hashes() {
+        let dir = tempfile::tempdir().expect("temporary directory");
+        let media = store(&dir, 8192);
+
+        for hash in ["../secret", "ABCDEF", &"0".repeat(64)] {
+            let response = request(&media, hash, None).await;
+            assert_eq!(response.status(), http::StatusCode::NOT_FOUND);
+        }
+    }
+}
@@ -0,0 +1,651 @@
+use std::{collections::HashMap, ops::Not};
+
+use axum::{
+    extract::{Path, Query, State},
+    http,
+    response::{IntoResponse, Redirect, Response},
+};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use tokio::sync::OnceCell;
+
+use crate::{
+    Ctx, args, audit,
+    auth::{self, ClientInfo},
+    cookie, neo4j, totp,
+};
+
+// Tiempo que tiene el usuario para volver del provider
+const PENDING_TTL: jiff::SignedDuration = jiff::SignedDuration::from_mins(10);
+
+#[derive(Debug)]
+pub enum Error {
+    Http(reqwest::Error),
+    Url(url::ParseError),
+    Serde(serde_json::Error),
+}
+
+impl From<Error> for http::StatusCode {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::Http(err) => {
+                tracing::error!("Failed talking to OIDC provider {err:?}");
+                http::StatusCode::BAD_GATEWAY
+            }
+            Error::Url(err) => {
+                tracing::error!("OIDC provider sent an invalid endpoint {err:?}");
+                http::StatusCode::BAD_GATEWAY
+            }
+            Error::Serde(err) => {
+                tracing::error!("Failed (de)serializing pending OIDC login {err:?}");
+                http::StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(err: reqwest::Error) -> Self {
+        Error::Http(err)
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(err: url::ParseError) -> Self {
+        Error::Url(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Serde(err)
+    }
+}
+
+#[derive(Deserialize)]
+struct Metadata {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: String,
+}
+
+struct Provider {
+    config: args::OidcProvider,
+    // Se descubre la primera vez que alguien usa el provider
+    metadata: OnceCell<Metadata>,
+}
+
+// Lo que guardamos entre `start` y `callback` en el store de sesiones, asi el
+// callback puede llegar a cualquier replica. La llave es el HMAC del `state`
+#[derive(Serialize, Deserialize)]
+struct Pending {
+    provider: String,
+    verifier: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct UserInfo {
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    preferred_username: Option<String>,
+    given_name: Option<String>,
+    family_name: Option<String>,
+}
+
+pub struct Oidc {
+    http: reqwest::Client,
+    providers: HashMap<String, Provider>,
+}
+
+impl Oidc {
+    pub fn new(providers: Vec<args::OidcProvider>) -> Self {
+        Oidc {
+            http: reqwest::Client::new(),
+            providers: providers
+                .into_iter()
+                .map(|config| {
+                    (
+                        config.name.clone(),
+                        Provider {
+                            config,
+                            metadata: OnceCell::new(),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    async fn metadata<'p>(&self, provider: &'p Provider) -> Result<&'p Metadata, Error> {
+        provider
+            .metadata
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{issuer}/.well-known/openid-configuration",
+                    issuer = provider.config.issuer.trim_end_matches('/')
+                );
+
+                Ok::<_, Error>(
+                    self.http
+                        .get(url)
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .json()
+                        .await?,
+                )
+            })
+            .await
+    }
+}
+
+fn pending_key(ctx: &Ctx, state: &str) -> String {
+    format!("oidc:{hash}", hash = auth::hash_token(ctx, state))
+}
+
+fn redirect_uri(ctx: &Ctx, provider: &str) -> String {
+    format!(
+        "{api_url}/auth/oidc/{provider}/callback",
+        api_url = ctx.auth.api_url.trim_end_matches('/')
+    )
+}
+
+fn pkce_challenge(verifier: &str) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .encode(sha2::Sha256::digest(verifier.as_bytes()))
+}
+
+fn authorization_url(
+    metadata: &Metadata,
+    provider: &Provider,
+    redirect_uri: &str,
+    state: &str,
+    challenge: &str,
+) -> Result<url::Url, Error> {
+    Ok(url::Url::parse_with_params(
+        &metadata.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", provider.config.client_id.as_str()),
+            ("redirect_uri", redirect_uri),
+            ("scope", "openid email profile"),
+            ("state", state),
+            ("code_challenge", challenge),
+            ("code_challenge_method", "S256"),
+        ],
+    )?)
+}
+
+pub async fn start(State(ctx): State<Ctx>, Path(name): Path<String>) -> Result<Response, Response> {
+    let Some(provider) = ctx.oidc.providers.get(&name) else {
+        Err(http::StatusCode::NOT_FOUND.into_response())?
+    };
+
+    let metadata = ctx
+        .oidc
+        .metadata(provider)
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let state = auth::generate_random_token::<32>();
+    let verifier = auth::generate_random_token::<64>();
+    let challenge = pkce_challenge(&verifier);
+
+    let pending = serde_json::to_string(&Pending {
+        provider: name.clone(),
+        verifier,
+    })
+    .map_err(Error::from)
+    .map_err(http::StatusCode::from)
+    .map_err(|res| res.into_response())?;
+
+    ctx.sessions
+        .put_pending(
+            &pending_key(&ctx, &state),
+            &pending,
+            jiff::Timestamp::now()
+                .checked_add(PENDING_TTL)
+                .expect("in overflows we do not believe"),
+        )
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let url = authorization_url(
+        metadata,
+        provider,
+        &redirect_uri(&ctx, &name),
+        &state,
+        &challenge,
+    )
+    .map_err(http::StatusCode::from)
+    .map_err(|res| res.into_response())?;
+
+    Ok((
+        cookie::oidc_state(&ctx, &state, PENDING_TTL.as_secs()),
+        Redirect::to(url.as_str()),
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+pub struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+pub async fn callback(
+    State(ctx): State<Ctx>,
+    Path(name): Path<String>,
+    client: ClientInfo,
+    headers: http::HeaderMap,
+    Query(params): Query<CallbackParams>,
+) -> Result<Response, Response> {
+    if let Some(error) = params.error {
+        tracing::warn!("OIDC provider {name} rejected the login with {error}");
+        Err(http::StatusCode::UNAUTHORIZED.into_response())?
+    }
+
+    let (Some(code), Some(state)) = (params.code, params.state) else {
+        Err(http::StatusCode::BAD_REQUEST.into_response())?
+    };
+
+    let Some(provider) = ctx.oidc.providers.get(&name) else {
+        Err(http::StatusCode::NOT_FOUND.into_response())?
+    };
+
+    // Sin esto alguien podria terminar el flujo con su cuenta del provider y
+    // mandarle el link a otro para meterlo a su cuenta
+    if cookie::read(&headers, cookie::OIDC_STATE)
+        .is_none_or(|cookie| cookie::same_value(cookie, &state).not())
+    {
+        tracing::warn!("OIDC callback for {name} came from a browser that did not start it");
+        Err(http::StatusCode::UNAUTHORIZED.into_response())?
+    }
+
+    // El `state` es de un solo uso aunque el resto del flujo falle
+    let pending = ctx
+        .sessions
+        .take_pending(&pending_key(&ctx, &state))
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let Some(pending) = pending
+        .map(|pending| serde_json::from_str::<Pending>(&pending))
+        .transpose()
+        .map_err(Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+        .filter(|pending| pending.provider == name)
+    else {
+        Err(http::StatusCode::UNAUTHORIZED.into_response())?
+    };
+
+    let info = fetch_user_info(
+        &ctx.oidc,
+        provider,
+        &redirect_uri(&ctx, &name),
+        &code,
+        &pending.verifier,
+    )
+    .await
+    .map_err(http::StatusCode::from)
+    .map_err(|res| res.into_response())?;
+
+    let Some(email) = info.email.as_deref().filter(|_| info.email_verified) else {
+        tracing::warn!("OIDC provider {name} did not vouch for the user's mail");
+        Err(http::StatusCode::FORBIDDEN.into_response())?
+    };
+
+    let username = find_or_create_user(&ctx, email, &info, &client).await?;
+    let app_url = ctx.auth.app_url.trim_end_matches('/');
+
+    // Los tokens van en el fragmento para que no terminen en logs de nadie
+    if let Some(challenge) = totp::start_challenge(&ctx, &username).await? {
+        return Ok((
+            cookie::clear_oidc_state(&ctx),
+            Redirect::to(&format!(
+                "{app_url}/oidc#challenge={challenge}&expires_in={expires_in}",
+                challenge = challenge.challenge,
+                expires_in = challenge.expires_in,
+            )),
+        )
+            .into_response());
+    }
+
+    let event = audit::Event::new(
+        audit::Kind::Signin,
+        audit::Outcome::Success,
+        &username,
+        &client,
+    );
+    let token = auth::issue_session(&ctx, &username, client)
+        .await
+        .map_err(|res| res.into_response())?;
+
+    ctx.audit
+        .record(event)
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    // En modo cookies solo el CSRF token va en el fragmento, la cookie es del
+    // dominio de la API y el frontend no siempre la puede leer
+    if ctx.auth.cookie_sessions {
+        let csrf_token = auth::generate_random_token::<32>();
+
+        return Ok((
+            cookie::clear_oidc_state(&ctx),
+            cookie::session_cookies(&ctx, &token, &csrf_token),
+            Redirect::to(&format!("{app_url}/oidc#csrf_token={csrf_token}")),
+        )
+            .into_response());
+    }
+
+    Ok((
+        cookie::clear_oidc_state(&ctx),
+        Redirect::to(&format!(
+            "{app_url}/oidc#token={token}&refresh_token={refresh_token}&expires_in={expires_in}",
+            token = token.token,
+            refresh_token = token.refresh_token,
+            expires_in = token.expires_in,
+        )),
+    )
+        .into_response())
+}
+
+async fn fetch_user_info(
+    oidc: &Oidc,
+    provider: &Provider,
+    redirect_uri: &str,
+    code: &str,
+    verifier: &str,
+) -> Result<UserInfo, Error> {
+    let metadata = oidc.metadata(provider).await?;
+
+    let token: TokenResponse = oidc
+        .http
+        .post(&metadata.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", provider.config.client_id.as_str()),
+            ("client_secret", provider.config.client_secret.as_str()),
+            ("code_verifier", verifier),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    // Pedimos los claims directo al provider, asi no hay que validar la firma
+    // del id_token
+    Ok(oidc
+        .http
+        .get(&metadata.userinfo_endpoint)
+        .bearer_auth(token.access_token)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+async fn find_user_by_mail(ctx: &Ctx, email: &str) -> Result<Option<String>, Response> {
+    // El provider ya verifico el correo, no hace falta nuestro link
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    mail_key: $mail_key
+                })
+                WHERE u.deletion_due IS NULL
+                SET u.verified = true
+                REMOVE u.verify_token, u.verify_sent_on, u.verify_expires_on
+                RETURN u.username AS username"#,
+            ))
+            .param("mail_key", auth::lookup_key(email)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    Ok(row.and_then(|row| row.get::<String>("username").ok()))
+}
+
+// Los usuarios creados asi no tienen contraseña, solo entran por el provider
+// o despues de un reset
+async fn find_or_create_user(
+    ctx: &Ctx,
+    email: &str,
+    info: &UserInfo,
+    client: &ClientInfo,
+) -> Result<String, Response> {
+    let base: String = info
+        .preferred_username
+        .as_deref()
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default())
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, '_' | '.' | '-'))
+        .collect();
+    let base = if base.is_empty() {
+        String::from("user")
+    } else {
+        base
+    };
+
+    for attempt in 0..5 {
+        if let Some(username) = find_user_by_mail(ctx, email).await? {
+            return Ok(username);
+        }
+
+        let username = if attempt == 0 {
+            base.clone()
+        } else {
+            format!("{base}{suffix}", suffix = rand::random_range(1000..10000))
+        };
+
+        let created = ctx
+            .neo4j
+            .run(
+                neo4rs::Query::new(String::from(
+                    r#"CREATE (u:User {
+                        username: $username,
+                        username_key: $username_key,
+                        mail: $mail,
+                        mail_key: $mail_key,
+                        first_name: $first_name,
+                        last_name: $last_name,
+                        verified: true
+                    })"#,
+                ))
+                .param("username", username.as_str())
+                .param("username_key", auth::lookup_key(&username))
+                .param("mail", email)
+                .param("mail_key", auth::lookup_key(email))
+                .param("first_name", info.given_name.as_deref())
+                .param("last_name", info.family_name.as_deref()),
+            )
+            .await;
+
+        // Si el username ya existe probamos con otro, si fue el correo la
+        // siguiente vuelta lo encuentra
+        match created.map_err(neo4j::Error::from) {
+            Ok(()) => {
+                ctx.audit
+                    .record(audit::Event::new(
+                        audit::Kind::Signup,
+                        audit::Outcome::Success,
+                        &username,
+                        client,
+                    ))
+                    .await
+                    .map_err(http::StatusCode::from)
+                    .map_err(|res| res.into_response())?;
+
+                return Ok(username);
+            }
+            Err(neo4j::Error::Client(neo4j::ClientError::ContraintValidation)) => continue,
+            Err(err) => Err(http::StatusCode::from(err).into_response())?,
+        }
+    }
+
+    Err(http::StatusCode::CONFLICT.into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{Form, routing};
+
+    use super::*;
+
+    const REDIRECT_URI: &str = "https://orbitly.test/api/auth/oidc/mock/callback";
+
+    // Provider falso: solo entrega tokens para `code-123` y el verifier cuyo
+    // challenge se le paso al autorizar
+    async fn stub_provider(challenge: Arc<Mutex<Option<String>>>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+
+        let discovery = serde_json::json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{issuer}/authorize"),
+            "token_endpoint": format!("{issuer}/token"),
+            "userinfo_endpoint": format!("{issuer}/userinfo"),
+        });
+
+        let app = axum::Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                routing::get(move || async move { axum::Json(discovery) }),
+            )
+            .route(
+                "/token",
+                routing::post(
+                    move |Form(form): Form<HashMap<String, String>>| async move {
+                        let field = |name: &str| form.get(name).map(String::as_str);
+                        let expected = challenge.lock().unwrap().clone();
+
+                        let valid = field("grant_type") == Some("authorization_code")
+                            && field("code") == Some("code-123")
+                            && field("redirect_uri") == Some(REDIRECT_URI)
+                            && field("client_id") == Some("orbitly")
+                            && field("client_secret") == Some("secret")
+                            && field("code_verifier").map(pkce_challenge) == expected;
+
+                        if valid.not() {
+                            return http::StatusCode::BAD_REQUEST.into_response();
+                        }
+
+                        axum::Json(serde_json::json!({
+                            "access_token": "access-123",
+                            "token_type": "Bearer",
+                        }))
+                        .into_response()
+                    },
+                ),
+            )
+            .route(
+                "/userinfo",
+                routing::get(|headers: http::HeaderMap| async move {
+                    if headers.get(http::header::AUTHORIZATION)
+                        != Some(&http::HeaderValue::from_static("Bearer access-123"))
+                    {
+                        return http::StatusCode::UNAUTHORIZED.into_response();
+                    }
+
+                    axum::Json(serde_json::json!({
+                        "sub": "42",
+                        "email": "ana@example.com",
+                        "email_verified": true,
+                        "preferred_username": "ana",
+                        "given_name": "Ana",
+                    }))
+                    .into_response()
+                }),
+            );
+
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        issuer
+    }
+
+    #[tokio::test]
+    async fn callback_exchanges_the_code_with_the_pkce_verifier() {
+        let challenge = Arc::new(Mutex::new(None));
+        let issuer = stub_provider(challenge.clone()).await;
+
+        let oidc = Oidc::new(vec![args::OidcProvider {
+            name: String::from("mock"),
+            issuer,
+            client_id: String::from("orbitly"),
+            client_secret: String::from("secret"),
+        }]);
+        let provider = &oidc.providers["mock"];
+        let metadata = oidc.metadata(provider).await.unwrap();
+
+        let verifier = auth::generate_random_token::<64>();
+        let url = authorization_url(
+            metadata,
+            provider,
+            REDIRECT_URI,
+            "state-123",
+            &pkce_challenge(&verifier),
+        )
+        .unwrap();
+        let params = url.query_pairs().into_owned().collect::<HashMap<_, _>>();
+
+        assert_eq!(params["client_id"], "orbitly");
+        assert_eq!(params["redirect_uri"], REDIRECT_URI);
+        assert_eq!(params["state"], "state-123");
+        assert_eq!(params["code_challenge_method"], "S256");
+
+        // El usuario autoriza y el provider recuerda el challenge
+        *challenge.lock().unwrap() = Some(params["code_challenge"].clone());
+
+        let info = fetch_user_info(&oidc, provider, REDIRECT_URI, "code-123", &verifier)
+            .await
+            .unwrap();
+
+        assert_eq!(info.email.as_deref(), Some("ana@example.com"));
+        assert!(info.email_verified);
+        assert_eq!(info.preferred_username.as_deref(), Some("ana"));
+        assert_eq!(info.given_name.as_deref(), Some("Ana"));
+        assert_eq!(info.family_name, None);
+
+        let wrong_verifier = auth::generate_random_token::<64>();
+        assert!(
+            fetch_user_info(&oidc, provider, REDIRECT_URI, "code-123", &wrong_verifier)
+                .await
+                .is_err()
+        );
+        assert!(
+            fetch_user_info(&oidc, provider, REDIRECT_URI, "code-456", &verifier)
+                .await
+                .is_err()
+        );
+    }
+}
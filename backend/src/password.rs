@@ -0,0 +1,247 @@
+use std::{
+    collections::HashSet,
+    ops::Not,
+    sync::{LazyLock, OnceLock},
+};
+
+use axum::{
+    http,
+    response::{IntoResponse, Response},
+};
+
+use crate::{args, auth};
+
+// Unos cientos de las contraseñas mas usadas, una por linea y en minusculas.
+// No es una lista de filtradas, solo cubre lo mas obvio cuando no se configura
+// `COMMON_PASSWORDS_FILE`
+static BUNDLED_PASSWORDS: LazyLock<HashSet<&'static str>> = LazyLock::new(|| {
+    include_str!("../assets/common-passwords.txt")
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.is_empty().not())
+        .collect()
+});
+
+// La lista grande que configura el operador, p. ej. el top 100k de SecLists
+static COMMON_PASSWORDS: OnceLock<HashSet<String>> = OnceLock::new();
+
+// Se llama una vez al arrancar, regresa cuantas contraseñas se cargaron
+pub fn load_common_passwords(path: &std::path::Path) -> std::io::Result<usize> {
+    let passwords = std::fs::read_to_string(path)?
+        .lines()
+        .map(|line| line.trim().to_lowercase())
+        .filter(|line| line.is_empty().not())
+        .collect::<HashSet<_>>();
+    let loaded = passwords.len();
+
+    if COMMON_PASSWORDS.set(passwords).is_err() {
+        tracing::warn!("Common passwords were already loaded, ignoring {path:?}");
+    }
+
+    Ok(loaded)
+}
+
+fn is_common(password: &str) -> bool {
+    let password = password.to_lowercase();
+
+    BUNDLED_PASSWORDS.contains(password.as_str())
+        || COMMON_PASSWORDS
+            .get()
+            .is_some_and(|passwords| passwords.contains(&password))
+}
+
+#[derive(serde::Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    MinLength,
+    CharacterClasses,
+    Common,
+}
+
+// Se regresa como JSON para que el cliente pueda decir que regla fallo
+#[derive(serde::Serialize)]
+pub struct WeakPassword {
+    rule: Rule,
+    min_length: usize,
+    min_classes: usize,
+}
+
+impl IntoResponse for WeakPassword {
+    fn into_response(self) -> Response {
+        (http::StatusCode::UNPROCESSABLE_ENTITY, axum::Json(self)).into_response()
+    }
+}
+
+// Minusculas, mayusculas, digitos y cualquier otro caracter
+fn character_classes(password: &str) -> usize {
+    let classes = [
+        password.chars().any(char::is_lowercase),
+        password.chars().any(char::is_uppercase),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| c.is_alphanumeric().not()),
+    ];
+
+    classes.into_iter().filter(|&class| class).count()
+}
+
+pub fn check(auth: &args::Auth, password: &str) -> Result<(), WeakPassword> {
+    let fail = |rule| WeakPassword {
+        rule,
+        min_length: auth.password_min_length,
+        min_classes: auth.password_min_classes,
+    };
+
+    if password.chars().count() < auth.password_min_length {
+        Err(fail(Rule::MinLength))?
+    }
+
+    if character_classes(password) < auth.password_min_classes {
+        Err(fail(Rule::CharacterClasses))?
+    }
+
+    if is_common(password) {
+        Err(fail(Rule::Common))?
+    }
+
+    Ok(())
+}
+
+pub enum Verification {
+    Invalid,
+    Valid,
+    // Correcta pero guardada con un formato o parametros viejos, hay que
+    // volver a hashearla
+    Outdated,
+}
+
+impl Verification {
+    pub fn is_valid(&self) -> bool {
+        matches!(self, Verification::Valid | Verification::Outdated)
+    }
+}
+
+// Los scripts de importacion marcan asi las contraseñas que venian en claro,
+// cualquier otro valor sin formato conocido se rechaza
+const PLAINTEXT_PREFIX: &str = "plain:";
+
+// Se verifica contra este hash cuando no hay uno de `password_auth`, asi todas
+// las cuentas tardan lo mismo
+pub static DUMMY_PASSWORD_HASH: LazyLock<String> =
+    LazyLock::new(|| password_auth::generate_hash(auth::generate_random_token::<32>()));
+
+// Ademas de los hashes de `password_auth` acepta lo que dejaron los scripts
+// de importacion: bcrypt, SHA-256 en hex sin sal y contraseñas en claro con
+// `plain:`
+pub fn verify(password: &str, stored: &str) -> Verification {
+    let valid = if stored.starts_with("$argon2")
+        || stored.starts_with("$scrypt")
+        || stored.starts_with("$pbkdf2")
+    {
+        if password_auth::verify_password(password, stored).is_err() {
+            return Verification::Invalid;
+        }
+
+        if password_auth::is_hash_obsolete(stored)
+            .unwrap_or(true)
+            .not()
+        {
+            return Verification::Valid;
+        }
+
+        true
+    } else if ["$2a$", "$2b$", "$2y$"]
+        .iter()
+        .any(|prefix| stored.starts_with(prefix))
+    {
+        bcrypt::verify(password, stored).unwrap_or_default()
+    } else {
+        // Sin esto las cuentas importadas responden al instante y se distinguen
+        // de las demas
+        let _ = password_auth::verify_password(password, &DUMMY_PASSWORD_HASH);
+
+        if let Some(plaintext) = stored.strip_prefix(PLAINTEXT_PREFIX) {
+            constant_time_eq(password, plaintext)
+        } else if stored.len() == 64 && stored.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            constant_time_eq(&hex_sha256(password), &stored.to_ascii_lowercase())
+        } else {
+            tracing::warn!("Rejecting signin against a password in an unknown format");
+            false
+        }
+    };
+
+    if valid {
+        Verification::Outdated
+    } else {
+        Verification::Invalid
+    }
+}
+
+fn hex_sha256(value: &str) -> String {
+    use sha2::Digest;
+
+    sha2::Sha256::digest(value.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+// Comparar los digests no filtra en cuanto tiempo difieren los originales
+fn constant_time_eq(left: &str, right: &str) -> bool {
+    use sha2::Digest;
+
+    sha2::Sha256::digest(left.as_bytes()) == sha2::Sha256::digest(right.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> args::Auth {
+        <args::Auth as clap::Parser>::try_parse_from(["orbitly", "--token-hash-key", "key"])
+            .expect("defaults are valid")
+    }
+
+    fn failed_rule(password: &str) -> Option<&'static str> {
+        check(&policy(), password)
+            .err()
+            .map(|weak| match weak.rule {
+                Rule::MinLength => "min_length",
+                Rule::CharacterClasses => "character_classes",
+                Rule::Common => "common",
+            })
+    }
+
+    #[test]
+    fn check_applies_every_rule() {
+        assert_eq!(failed_rule("Ab1"), Some("min_length"));
+        assert_eq!(failed_rule("abcdefghij"), Some("character_classes"));
+        assert_eq!(failed_rule("Password"), Some("common"));
+        assert_eq!(failed_rule("correct horse battery"), None);
+    }
+
+    #[test]
+    fn verify_accepts_password_auth_hashes() {
+        let stored = password_auth::generate_hash("hunter22");
+
+        assert!(matches!(verify("hunter22", &stored), Verification::Valid));
+        assert!(matches!(verify("hunter23", &stored), Verification::Invalid));
+    }
+
+    #[test]
+    fn verify_asks_to_rehash_legacy_formats() {
+        let bcrypt = bcrypt::hash("hunter22", 4).unwrap();
+        let sha = hex_sha256("hunter22").to_ascii_uppercase();
+
+        for stored in [bcrypt.as_str(), sha.as_str(), "plain:hunter22"] {
+            assert!(matches!(verify("hunter22", stored), Verification::Outdated));
+            assert!(matches!(verify("hunter23", stored), Verification::Invalid));
+        }
+    }
+
+    #[test]
+    fn verify_rejects_unknown_formats() {
+        for stored in ["hunter22", "$6$salt$hash", "$1$salt$hash", "{SSHA}aGFzaA=="] {
+            assert!(matches!(verify(stored, stored), Verification::Invalid));
+        }
+    }
+}
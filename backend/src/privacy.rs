@@ -0,0 +1,236 @@
+use std::ops::Not;
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http,
+    response::{IntoResponse, Response},
+};
+use facet::Facet;
+
+use crate::{
+    Ctx,
+    auth::{self, Session},
+    json::Json,
+    neo4j,
+};
+
+#[derive(serde::Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    Public,
+    // Solo los usuarios con los que el dueño hizo match
+    MatchesOnly,
+    Private,
+}
+
+impl Visibility {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::MatchesOnly => "matches_only",
+            Visibility::Private => "private",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "public" => Some(Visibility::Public),
+            "matches_only" => Some(Visibility::MatchesOnly),
+            "private" => Some(Visibility::Private),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Section {
+    // Nombres, descripcion y avatar
+    Profile,
+    Interests,
+    Matches,
+}
+
+impl Section {
+    fn property(self) -> &'static str {
+        match self {
+            Section::Profile => "privacy_profile",
+            Section::Interests => "privacy_interests",
+            Section::Matches => "privacy_matches",
+        }
+    }
+}
+
+// Predicado de Cypher para usar dentro de otros queries, `owner` y `viewer` son
+// variables ya ligadas a nodos `User`. Sin la propiedad todo es publico
+pub fn visible_to(owner: &str, viewer: &str, section: Section) -> String {
+    format!(
+        r#"({owner} = {viewer}
+            OR coalesce({owner}.{property}, 'public') = 'public'
+            OR (coalesce({owner}.{property}, 'public') = 'matches_only'
+                AND EXISTS {{ ({owner})-[:MATCHES]->({viewer}) }}))"#,
+        property = section.property(),
+    )
+}
+
+// FORBIDDEN si `viewer` no puede ver esa seccion de `owner`. Si alguno no
+// existe lo dejamos pasar, el query que sigue no va a encontrar nada
+pub async fn check(ctx: &Ctx, viewer: &str, owner: &str, section: Section) -> Result<(), Response> {
+    let mut stream = ctx
+        .neo4j
+        .execute_read(
+            neo4rs::Query::new(format!(
+                r#"MATCH (owner:User {{
+                    username_key: $owner
+                }}), (viewer:User {{
+                    username_key: $viewer
+                }})
+                RETURN {visible} AS visible"#,
+                visible = visible_to("owner", "viewer", section),
+            ))
+            .param("owner", auth::lookup_key(owner))
+            .param("viewer", auth::lookup_key(viewer)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    if row.is_some_and(|row| row.get::<bool>("visible").unwrap_or_default().not()) {
+        Err(http::StatusCode::FORBIDDEN.into_response())?
+    }
+
+    Ok(())
+}
+
+// Solo se cambian las secciones que vengan, las demas se quedan como estan
+#[derive(Facet, Clone, Copy)]
+struct PrivacyReq<'inp> {
+    #[facet(default)]
+    profile: Option<&'inp str>,
+    #[facet(default)]
+    interests: Option<&'inp str>,
+    #[facet(default)]
+    matches: Option<&'inp str>,
+}
+
+#[derive(serde::Serialize)]
+pub struct PrivacySettings {
+    profile: Visibility,
+    interests: Visibility,
+    matches: Visibility,
+}
+
+pub async fn get_privacy(
+    State(ctx): State<Ctx>,
+    session: Session,
+) -> Result<axum::Json<PrivacySettings>, Response> {
+    let mut stream = ctx
+        .neo4j
+        .execute_read(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                })
+                RETURN u.privacy_profile AS profile,
+                    u.privacy_interests AS interests,
+                    u.privacy_matches AS matches"#,
+            ))
+            .param("username_key", auth::lookup_key(&session.username)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let Some(row) = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+    else {
+        Err(http::StatusCode::NOT_FOUND.into_response())?
+    };
+
+    Ok(axum::Json(read_settings(&row)))
+}
+
+fn read_settings(row: &neo4rs::Row) -> PrivacySettings {
+    let read = |column: &str| {
+        row.get::<String>(column)
+            .ok()
+            .and_then(|value| Visibility::parse(&value))
+            .unwrap_or(Visibility::Public)
+    };
+
+    PrivacySettings {
+        profile: read("profile"),
+        interests: read("interests"),
+        matches: read("matches"),
+    }
+}
+
+pub async fn put_privacy(
+    State(ctx): State<Ctx>,
+    session: Session,
+    bytes: Bytes,
+) -> Result<axum::Json<PrivacySettings>, Response> {
+    let Json(req): Json<PrivacyReq> =
+        Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+
+    // `None` si no vino, un valor desconocido es un error
+    let parse = |value: Option<&str>| match value {
+        Some(value) => Visibility::parse(value).map(Some),
+        None => Some(None),
+    };
+
+    let (Some(profile), Some(interests), Some(matches)) =
+        (parse(req.profile), parse(req.interests), parse(req.matches))
+    else {
+        Err(http::StatusCode::BAD_REQUEST.into_response())?
+    };
+
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                })
+                SET u.privacy_profile = coalesce($profile, u.privacy_profile),
+                    u.privacy_interests = coalesce($interests, u.privacy_interests),
+                    u.privacy_matches = coalesce($matches, u.privacy_matches)
+                RETURN u.privacy_profile AS profile,
+                    u.privacy_interests AS interests,
+                    u.privacy_matches AS matches"#,
+            ))
+            .param("username_key", auth::lookup_key(&session.username))
+            .param("profile", profile.map(Visibility::as_str))
+            .param("interests", interests.map(Visibility::as_str))
+            .param("matches", matches.map(Visibility::as_str)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let Some(row) = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+    else {
+        Err(http::StatusCode::NOT_FOUND.into_response())?
+    };
+
+    Ok(axum::Json(read_settings(&row)))
+}
@@ -0,0 +1,339 @@
+use std::{collections::HashMap, ops::Not};
+
+use axum::{
+    body::Bytes,
+    extract::{Multipart, State},
+    http,
+    response::{IntoResponse, Response},
+};
+use facet::Facet;
+
+use crate::{
+    Ctx,
+    auth::{self, Session},
+    json::Json,
+    media, neo4j,
+};
+
+const MAX_NAME_LENGTH: usize = 50;
+const MAX_DESCRIPTION_LENGTH: usize = 500;
+// El nombre de pais mas largo ronda los 56 caracteres
+const MAX_COUNTRY_LENGTH: usize = 60;
+const MAX_AVATAR_LENGTH: usize = 2048;
+
+// Un campo que no viene se queda como esta, uno vacio se borra
+#[derive(Facet, Clone, Copy)]
+struct PatchReq<'inp> {
+    #[facet(default)]
+    first_name: Option<&'inp str>,
+    #[facet(default)]
+    last_name: Option<&'inp str>,
+    #[facet(default)]
+    description: Option<&'inp str>,
+    #[facet(default)]
+    avatar: Option<&'inp str>,
+    #[facet(default)]
+    country: Option<&'inp str>,
+}
+
+#[derive(serde::Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    TooLong,
+    InvalidCharacters,
+    InvalidUrl,
+}
+
+#[derive(serde::Serialize)]
+pub struct FieldError {
+    field: &'static str,
+    rule: Rule,
+}
+
+// Igual que con las contraseñas el cliente recibe que regla fallo, aqui por
+// cada campo
+#[derive(serde::Serialize)]
+pub struct InvalidProfile {
+    errors: Vec<FieldError>,
+}
+
+impl IntoResponse for InvalidProfile {
+    fn into_response(self) -> Response {
+        (http::StatusCode::UNPROCESSABLE_ENTITY, axum::Json(self)).into_response()
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Profile {
+    username: String,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    description: Option<String>,
+    avatar: Option<String>,
+    avatar_thumbnail: Option<String>,
+    country: Option<String>,
+}
+
+fn check_text(value: &str, max_length: usize, multiline: bool) -> Option<Rule> {
+    if value.chars().count() > max_length {
+        return Some(Rule::TooLong);
+    }
+
+    // Los saltos de linea solo valen en la descripcion
+    if value
+        .chars()
+        .any(|c| c.is_control() && (multiline && c == '\n').not())
+    {
+        return Some(Rule::InvalidCharacters);
+    }
+
+    None
+}
+
+fn check_avatar(value: &str) -> Option<Rule> {
+    if value.len() > MAX_AVATAR_LENGTH {
+        return Some(Rule::TooLong);
+    }
+
+    match url::Url::parse(value) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.host().is_some() => None,
+        _ => Some(Rule::InvalidUrl),
+    }
+}
+
+pub async fn patch_me(
+    State(ctx): State<Ctx>,
+    session: Session,
+    bytes: Bytes,
+) -> Result<axum::Json<Profile>, Response> {
+    let Json(req): Json<PatchReq> = Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+
+    let fields = [
+        ("first_name", req.first_name),
+        ("last_name", req.last_name),
+        ("description", req.description),
+        ("avatar", req.avatar),
+        ("country", req.country),
+    ];
+
+    if fields.iter().all(|(_, value)| value.is_none()) {
+        Err(http::StatusCode::BAD_REQUEST.into_response())?
+    }
+
+    let mut errors = vec![];
+    // Un valor nulo en `SET u += $changes` borra la propiedad
+    let mut changes: HashMap<String, Option<String>> = HashMap::new();
+
+    for (field, value) in fields {
+        let Some(value) = value.map(str::trim) else {
+            continue;
+        };
+
+        if value.is_empty() {
+            changes.insert(field.to_string(), None);
+            continue;
+        }
+
+        let rule = match field {
+            "first_name" | "last_name" => check_text(value, MAX_NAME_LENGTH, false),
+            "description" => check_text(value, MAX_DESCRIPTION_LENGTH, true),
+            "country" => check_text(value, MAX_COUNTRY_LENGTH, false),
+            "avatar" => check_avatar(value),
+            _ => unreachable!("only the fields above"),
+        };
+
+        match rule {
+            Some(rule) => errors.push(FieldError { field, rule }),
+            None => {
+                changes.insert(field.to_string(), Some(value.to_string()));
+            }
+        }
+    }
+
+    if errors.is_empty().not() {
+        Err(InvalidProfile { errors }.into_response())?
+    }
+
+    // La miniatura solo existe para avatares subidos, con una URL ya no aplica
+    if changes.contains_key("avatar") {
+        changes.insert(String::from("avatar_thumbnail"), None);
+    }
+
+    let profile = update_profile(&ctx, &session.username, changes).await?;
+
+    Ok(axum::Json(profile))
+}
+
+async fn update_profile(
+    ctx: &Ctx,
+    username: &str,
+    changes: HashMap<String, Option<String>>,
+) -> Result<Profile, Response> {
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                })
+                SET u += $changes
+                RETURN u.username AS username,
+                    u.first_name AS first_name,
+                    u.last_name AS last_name,
+                    u.description AS description,
+                    u.avatar AS avatar,
+                    u.avatar_thumbnail AS avatar_thumbnail,
+                    u.country AS country"#,
+            ))
+            .param("username_key", auth::lookup_key(username))
+            .param("changes", changes),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let Some(row) = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+    else {
+        Err(http::StatusCode::NOT_FOUND.into_response())?
+    };
+
+    row.to::<Profile>().map_err(|err| {
+        tracing::error!("Failed deserializing Profile {err}");
+        http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })
+}
+
+// Espera el archivo en el campo `avatar` del multipart
+pub async fn put_avatar(
+    State(ctx): State<Ctx>,
+    session: Session,
+    mut multipart: Multipart,
+) -> Result<axum::Json<Profile>, Response> {
+    let mut upload = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|err| err.into_response())?
+    {
+        if field.name() == Some("avatar") {
+            upload = Some(field.bytes().await.map_err(|err| err.into_response())?);
+            break;
+        }
+    }
+
+    let Some(upload) = upload else {
+        Err(http::StatusCode::BAD_REQUEST.into_response())?
+    };
+
+    if upload.len() > ctx.media.max_upload_bytes() {
+        Err(http::StatusCode::PAYLOAD_TOO_LARGE.into_response())?
+    }
+
+    // Decodificar y redimensionar bloquea, no lo hacemos en el runtime
+    let media = ctx.media.clone();
+    let variants = tokio::task::spawn_blocking(move || media.avatar_variants(&upload))
+        .await
+        .map_err(|err| {
+            tracing::error!("Avatar processing panicked {err}");
+            http::StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        })?
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let api_url = ctx.auth.api_url.trim_end_matches('/');
+    let mut changes = HashMap::new();
+    for (size, bytes) in variants {
+        let hash = ctx
+            .media
+            .put(bytes)
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        let field = if size == media::AVATAR_SIZE {
+            "avatar"
+        } else {
+            "avatar_thumbnail"
+        };
+        changes.insert(field.to_string(), Some(format!("{api_url}/media/{hash}")));
+    }
+
+    let profile = update_profile(&ctx, &session.username, changes).await?;
+
+    Ok(axum::Json(profile))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule_name(rule: Option<Rule>) -> Option<&'static str> {
+        rule.map(|rule| match rule {
+            Rule::TooLong => "too_long",
+            Rule::InvalidCharacters => "invalid_characters",
+            Rule::InvalidUrl => "invalid_url",
+        })
+    }
+
+    #[test]
+    fn check_text_counts_characters_not_bytes() {
+        let name = "ñ".repeat(MAX_NAME_LENGTH);
+
+        assert_eq!(rule_name(check_text(&name, MAX_NAME_LENGTH, false)), None);
+        assert_eq!(
+            rule_name(check_text(&format!("{name}a"), MAX_NAME_LENGTH, false)),
+            Some("too_long")
+        );
+    }
+
+    #[test]
+    fn check_text_allows_line_breaks_only_when_multiline() {
+        let text = "Me gusta el cine\ny la musica";
+
+        assert_eq!(
+            rule_name(check_text(text, MAX_DESCRIPTION_LENGTH, true)),
+            None
+        );
+        assert_eq!(
+            rule_name(check_text(text, MAX_NAME_LENGTH, false)),
+            Some("invalid_characters")
+        );
+
+        for text in ["tab\there", "nul\0", "bell\u{7}", "cr\r\n"] {
+            assert_eq!(
+                rule_name(check_text(text, MAX_DESCRIPTION_LENGTH, true)),
+                Some("invalid_characters")
+            );
+        }
+    }
+
+    #[test]
+    fn check_avatar_accepts_only_http_urls_with_a_host() {
+        for url in [
+            "https://cdn.example.com/avatar.png",
+            "http://localhost:8080/media/abc",
+        ] {
+            assert_eq!(rule_name(check_avatar(url)), None);
+        }
+
+        for url in [
+            "javascript:alert(1)",
+            "data:image/png;base64,AAAA",
+            "ftp://example.com/avatar.png",
+            "file:///etc/passwd",
+            "/media/abc",
+            "https://",
+        ] {
+            assert_eq!(rule_name(check_avatar(url)), Some("invalid_url"));
+        }
+
+        let long = format!("https://example.com/{}", "a".repeat(MAX_AVATAR_LENGTH));
+        assert_eq!(rule_name(check_avatar(&long)), Some("too_long"));
+    }
+}
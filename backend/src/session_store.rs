@@ -0,0 +1,1318 @@
+use std::{collections::HashMap, ops::Not, sync::Arc};
+
+use axum::http::StatusCode;
+use tokio::sync::Mutex;
+
+use crate::auth::{RefreshToken, Session, generate_random_token, lookup_key};
+
+#[derive(Debug)]
+pub enum Error {
+    Sled(std::io::Error),
+    Neo4j(neo4rs::Error),
+    Serde(serde_json::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// Cuantas llaves revisa la purga de sled antes de soltar el lock
+const PURGE_BATCH: usize = 500;
+
+impl From<Error> for StatusCode {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::Sled(err) => tracing::error!("Failed accessing sled tree {err:?}"),
+            Error::Neo4j(err) => tracing::error!("neo4j error {err:?}"),
+            Error::Serde(err) => tracing::error!("Failed (de)serializing Session {err:?}"),
+        }
+
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Sled(err)
+    }
+}
+
+impl From<neo4rs::Error> for Error {
+    fn from(err: neo4rs::Error) -> Self {
+        Error::Neo4j(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Serde(err)
+    }
+}
+
+// Una sesion se identifica por `(username, id)` y es dueña de un solo access
+// token a la vez, `insert` reemplaza el token anterior de la misma sesion. Los
+// tokens que recibe el store ya vienen hasheados
+#[async_trait::async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn get(&self, token: &str) -> Result<Option<Session>>;
+    async fn get_by_id(&self, username: &str, id: &str) -> Result<Option<Session>>;
+    async fn list(&self, username: &str) -> Result<Vec<Session>>;
+    async fn insert(&self, session: &Session) -> Result<()>;
+    // Como `insert` pero solo si la sesion todavia tiene `previous_token`, asi
+    // dos refresh simultaneos con el mismo token no rotan los dos. Regresa si
+    // se reemplazo
+    async fn rotate(&self, previous_token: &str, session: &Session) -> Result<bool>;
+    async fn remove(&self, username: &str, id: &str) -> Result<Option<Session>>;
+    // Solo actualiza `last_seen` si la sesion sigue existiendo con ese mismo
+    // token, nunca revive una sesion revocada ni pisa una rotacion
+    async fn touch(
+        &self,
+        username: &str,
+        id: &str,
+        token: &str,
+        now: jiff::Timestamp,
+    ) -> Result<()>;
+    async fn get_refresh(&self, refresh_token: &str) -> Result<Option<RefreshToken>>;
+    async fn remove_refresh(&self, refresh_token: &str) -> Result<()>;
+    // Datos de un solo uso entre dos requests, como el `state` de un login con
+    // OIDC. Viven aqui para que los vea cualquier replica que comparta el store
+    async fn put_pending(&self, key: &str, data: &str, expires_on: jiff::Timestamp) -> Result<()>;
+    // Lo borra al leerlo, `None` si no existe o ya expiro
+    async fn take_pending(&self, key: &str) -> Result<Option<String>>;
+    // Borra las sesiones expiradas, los refresh tokens huerfanos y los
+    // pendientes vencidos, regresa cuantas sesiones se borraron
+    async fn purge_expired(&self) -> Result<usize>;
+    // Hashea los tokens que se guardaron en claro antes de que existiera el
+    // HMAC, le da un id a las sesiones que no tienen y en Neo4j les pone
+    // `username_key`, regresa cuantas sesiones se migraron
+    async fn migrate_plaintext_tokens(&self, hash: &TokenHasher) -> Result<usize>;
+}
+
+pub type TokenHasher = dyn Fn(&str) -> String + Send + Sync;
+
+pub async fn sweep_expired(store: Arc<dyn SessionStore>, every: std::time::Duration) {
+    let mut interval = tokio::time::interval(every);
+
+    loop {
+        interval.tick().await;
+
+        match store.purge_expired().await {
+            Ok(purged) => {
+                tracing::info!("Purged {purged} expired sessions");
+                metrics::counter!("sessions_purged_total").increment(purged as u64);
+            }
+            Err(err) => tracing::error!("Session sweep failed {err:?}"),
+        }
+    }
+}
+
+pub struct SledStore {
+    trees: Mutex<SledTrees>,
+}
+
+struct SledTrees {
+    tokens: sled::Tree<1024>,
+    index: sled::Tree<1024>,
+    refresh: sled::Tree<1024>,
+    pending: sled::Tree<1024>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Pending {
+    data: String,
+    expires_on: jiff::Timestamp,
+}
+
+impl SledStore {
+    pub fn new(db: &sled::Db<1024>) -> std::io::Result<Self> {
+        Ok(SledStore {
+            trees: Mutex::new(SledTrees {
+                tokens: db.open_tree("tokens")?,
+                index: db.open_tree("tokens_by_user")?,
+                refresh: db.open_tree("refresh_tokens")?,
+                pending: db.open_tree("pending")?,
+            }),
+        })
+    }
+
+    // Recorre `tree` de `PURGE_BATCH` en `PURGE_BATCH` llaves y solo tiene el
+    // lock durante cada tanda, asi la purga no frena los logins. `purge`
+    // recibe cada llave con su valor y regresa si la borro
+    async fn purge_in_batches(
+        &self,
+        tree: fn(&SledTrees) -> &sled::Tree<1024>,
+        purge: impl Fn(&SledTrees, &[u8], &[u8]) -> Result<bool>,
+    ) -> Result<usize> {
+        let mut start = vec![];
+        let mut purged = 0;
+
+        loop {
+            let trees = self.trees.lock().await;
+
+            let batch = tree(&trees)
+                .range(start.as_slice()..)
+                .take(PURGE_BATCH)
+                .collect::<std::io::Result<Vec<_>>>()?;
+
+            for (key, value) in &batch {
+                if purge(&trees, key, value)? {
+                    purged += 1;
+                }
+            }
+
+            match batch.last() {
+                // La siguiente tanda empieza justo despues de la ultima llave
+                Some((last, _)) if batch.len() == PURGE_BATCH => {
+                    start = last.to_vec();
+                    start.push(0);
+                }
+                _ => return Ok(purged),
+            }
+        }
+    }
+}
+
+impl SledTrees {
+    fn write(&self, session: &Session) -> Result<()> {
+        let previous = self.index.insert(
+            user_index_key(&session.username, &session.id),
+            session.token_hash.as_bytes(),
+        )?;
+
+        if let Some(previous) = previous
+            && &*previous != session.token_hash.as_bytes()
+        {
+            self.tokens.remove(previous)?;
+        }
+
+        self.tokens
+            .insert(&session.token_hash, serde_json::to_vec(session)?)?;
+
+        if let Some(refresh_token) = &session.refresh_token_hash {
+            self.refresh
+                .insert(refresh_token, serde_json::to_vec(&refresh_entry(session))?)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Las llaves del indice son `username\0id` y su valor es el token, asi un
+// scan por prefijo regresa todas las sesiones de un usuario
+fn user_index_prefix(username: &str) -> Vec<u8> {
+    let mut prefix = username.as_bytes().to_vec();
+    prefix.push(0);
+    prefix
+}
+
+fn user_index_key(username: &str, id: &str) -> Vec<u8> {
+    let mut key = user_index_prefix(username);
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+// Los tokens en claro son alfanumericos de 50 caracteres, un HMAC-SHA256 en
+// hex siempre mide 64
+fn is_token_hash(token: &[u8]) -> bool {
+    token.len() == 64 && token.iter().all(|byte| byte.is_ascii_hexdigit())
+}
+
+fn read_session(bytes: &[u8]) -> Result<Session> {
+    Ok(serde_json::from_slice(bytes)?)
+}
+
+fn refresh_entry(session: &Session) -> RefreshToken {
+    RefreshToken {
+        username: session.username.clone(),
+        session: session.id.clone(),
+    }
+}
+
+#[async_trait::async_trait]
+impl SessionStore for SledStore {
+    async fn get(&self, token: &str) -> Result<Option<Session>> {
+        let trees = self.trees.lock().await;
+
+        trees
+            .tokens
+            .get(token)?
+            .map(|bytes| read_session(&bytes))
+            .transpose()
+    }
+
+    async fn get_by_id(&self, username: &str, id: &str) -> Result<Option<Session>> {
+        let trees = self.trees.lock().await;
+
+        let Some(token) = trees.index.get(user_index_key(username, id))? else {
+            return Ok(None);
+        };
+
+        trees
+            .tokens
+            .get(token)?
+            .map(|bytes| read_session(&bytes))
+            .transpose()
+    }
+
+    async fn list(&self, username: &str) -> Result<Vec<Session>> {
+        let trees = self.trees.lock().await;
+
+        let entries = trees
+            .index
+            .scan_prefix(user_index_prefix(username))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let mut sessions = vec![];
+        for (key, token) in entries {
+            match trees.tokens.get(&token)? {
+                Some(bytes) => sessions.push(read_session(&bytes)?),
+                None => {
+                    tracing::debug!("Dropping dangling session index entry");
+                    trees.index.remove(key)?;
+                }
+            }
+        }
+
+        Ok(sessions)
+    }
+
+    async fn insert(&self, session: &Session) -> Result<()> {
+        self.trees.lock().await.write(session)
+    }
+
+    async fn rotate(&self, previous_token: &str, session: &Session) -> Result<bool> {
+        let trees = self.trees.lock().await;
+
+        if trees
+            .index
+            .get(user_index_key(&session.username, &session.id))?
+            .is_none_or(|current| &*current != previous_token.as_bytes())
+        {
+            return Ok(false);
+        }
+
+        trees.write(session)?;
+
+        Ok(true)
+    }
+
+    async fn remove(&self, username: &str, id: &str) -> Result<Option<Session>> {
+        let trees = self.trees.lock().await;
+
+        let Some(token) = trees.index.remove(user_index_key(username, id))? else {
+            return Ok(None);
+        };
+
+        trees
+            .tokens
+            .remove(token)?
+            .map(|bytes| read_session(&bytes))
+            .transpose()
+    }
+
+    async fn touch(
+        &self,
+        username: &str,
+        id: &str,
+        token: &str,
+        now: jiff::Timestamp,
+    ) -> Result<()> {
+        let trees = self.trees.lock().await;
+
+        if trees
+            .index
+            .get(user_index_key(username, id))?
+            .is_none_or(|current| &*current != token.as_bytes())
+        {
+            return Ok(());
+        }
+
+        let Some(bytes) = trees.tokens.get(token)? else {
+            return Ok(());
+        };
+
+        let mut session = read_session(&bytes)?;
+        session.last_seen = Some(now);
+        trees.tokens.insert(token, serde_json::to_vec(&session)?)?;
+
+        Ok(())
+    }
+
+    async fn get_refresh(&self, refresh_token: &str) -> Result<Option<RefreshToken>> {
+        let trees = self.trees.lock().await;
+
+        match trees.refresh.get(refresh_token)? {
+            Some(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn remove_refresh(&self, refresh_token: &str) -> Result<()> {
+        self.trees.lock().await.refresh.remove(refresh_token)?;
+        Ok(())
+    }
+
+    async fn put_pending(&self, key: &str, data: &str, expires_on: jiff::Timestamp) -> Result<()> {
+        let pending = Pending {
+            data: data.to_string(),
+            expires_on,
+        };
+
+        self.trees
+            .lock()
+            .await
+            .pending
+            .insert(key, serde_json::to_vec(&pending)?)?;
+        Ok(())
+    }
+
+    async fn take_pending(&self, key: &str) -> Result<Option<String>> {
+        let Some(bytes) = self.trees.lock().await.pending.remove(key)? else {
+            return Ok(None);
+        };
+
+        let pending: Pending = serde_json::from_slice(&bytes)?;
+        Ok((pending.expires_on > jiff::Timestamp::now()).then_some(pending.data))
+    }
+
+    async fn purge_expired(&self) -> Result<usize> {
+        let expired = self
+            .purge_in_batches(
+                |trees| &trees.tokens,
+                |trees, token, bytes| {
+                    let session = match serde_json::from_slice::<Session>(bytes) {
+                        Ok(session) => session,
+                        Err(err) => {
+                            tracing::error!("Failed deserializing Session {err:?}");
+                            return Ok(false);
+                        }
+                    };
+
+                    if session.is_expired().not() {
+                        return Ok(false);
+                    }
+
+                    trees.tokens.remove(token)?;
+                    trees
+                        .index
+                        .remove(user_index_key(&session.username, &session.id))?;
+
+                    Ok(true)
+                },
+            )
+            .await?;
+
+        self.purge_in_batches(
+            |trees| &trees.refresh,
+            |trees, refresh_token, bytes| {
+                let family: RefreshToken = serde_json::from_slice(bytes)?;
+
+                if trees
+                    .index
+                    .contains_key(user_index_key(&family.username, &family.session))?
+                {
+                    return Ok(false);
+                }
+
+                trees.refresh.remove(refresh_token)?;
+                Ok(true)
+            },
+        )
+        .await?;
+
+        let now = jiff::Timestamp::now();
+        self.purge_in_batches(
+            |trees| &trees.pending,
+            |trees, key, bytes| {
+                if serde_json::from_slice::<Pending>(bytes)
+                    .is_ok_and(|pending| pending.expires_on < now)
+                    .not()
+                {
+                    return Ok(false);
+                }
+
+                trees.pending.remove(key)?;
+                Ok(true)
+            },
+        )
+        .await?;
+
+        Ok(expired)
+    }
+
+    async fn migrate_plaintext_tokens(&self, hash: &TokenHasher) -> Result<usize> {
+        let trees = self.trees.lock().await;
+
+        // Primero los refresh tokens, abajo se reescriben los de las sesiones
+        // que cambian de id y no deben pisarse con los viejos
+        let mut legacy_refresh = vec![];
+        for entry in trees.refresh.iter() {
+            let (refresh_token, bytes) = entry?;
+            if is_token_hash(&refresh_token).not() {
+                legacy_refresh.push((refresh_token, bytes));
+            }
+        }
+
+        for (refresh_token, bytes) in legacy_refresh {
+            trees.refresh.remove(&refresh_token)?;
+            match std::str::from_utf8(&refresh_token) {
+                Ok(refresh_token) => {
+                    trees.refresh.insert(hash(refresh_token), bytes)?;
+                }
+                Err(_) => tracing::warn!("Dropping refresh token that is not valid utf-8"),
+            }
+        }
+
+        let mut legacy = vec![];
+        for entry in trees.tokens.iter() {
+            let (token, bytes) = entry?;
+            let session = read_session(&bytes)?;
+            let plaintext = is_token_hash(&token).not();
+            if plaintext || session.id.is_empty() {
+                legacy.push((token, session, plaintext));
+            }
+        }
+
+        for (token, session, plaintext) in &mut legacy {
+            // Las sesiones de antes de los ids todas caen en `username\0` del
+            // indice, o ni siquiera estan indexadas. Cada una recibe su id
+            let renamed = session.id.is_empty();
+            if renamed {
+                trees.index.remove(user_index_key(&session.username, ""))?;
+                session.id = generate_random_token::<16>();
+            }
+
+            if *plaintext {
+                session.token_hash = hash(&session.token_hash);
+                session.refresh_token_hash = session
+                    .refresh_token_hash
+                    .as_deref()
+                    .map(|refresh_token| hash(refresh_token));
+            }
+
+            if renamed && let Some(refresh_token) = &session.refresh_token_hash {
+                trees
+                    .refresh
+                    .insert(refresh_token, serde_json::to_vec(&refresh_entry(session))?)?;
+            }
+
+            trees.tokens.remove(&*token)?;
+            trees
+                .tokens
+                .insert(&session.token_hash, serde_json::to_vec(&*session)?)?;
+            trees.index.insert(
+                user_index_key(&session.username, &session.id),
+                session.token_hash.as_bytes(),
+            )?;
+        }
+
+        Ok(legacy.len())
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    inner: Mutex<MemoryInner>,
+}
+
+#[derive(Default)]
+struct MemoryInner {
+    tokens: HashMap<String, Session>,
+    index: HashMap<(String, String), String>,
+    refresh: HashMap<String, RefreshToken>,
+    pending: HashMap<String, Pending>,
+}
+
+impl MemoryInner {
+    fn write(&mut self, session: &Session) {
+        let previous = self.index.insert(
+            (session.username.clone(), session.id.clone()),
+            session.token_hash.clone(),
+        );
+
+        if let Some(previous) = previous
+            && previous != session.token_hash
+        {
+            self.tokens.remove(&previous);
+        }
+
+        self.tokens
+            .insert(session.token_hash.clone(), session.clone());
+
+        if let Some(refresh_token) = &session.refresh_token_hash {
+            self.refresh
+                .insert(refresh_token.clone(), refresh_entry(session));
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl SessionStore for MemoryStore {
+    async fn get(&self, token: &str) -> Result<Option<Session>> {
+        Ok(self.inner.lock().await.tokens.get(token).cloned())
+    }
+
+    async fn get_by_id(&self, username: &str, id: &str) -> Result<Option<Session>> {
+        let inner = self.inner.lock().await;
+
+        Ok(inner
+            .index
+            .get(&(username.to_string(), id.to_string()))
+            .and_then(|token| inner.tokens.get(token))
+            .cloned())
+    }
+
+    async fn list(&self, username: &str) -> Result<Vec<Session>> {
+        Ok(self
+            .inner
+            .lock()
+            .await
+            .tokens
+            .values()
+            .filter(|session| session.username == username)
+            .cloned()
+            .collect())
+    }
+
+    async fn insert(&self, session: &Session) -> Result<()> {
+        self.inner.lock().await.write(session);
+        Ok(())
+    }
+
+    async fn rotate(&self, previous_token: &str, session: &Session) -> Result<bool> {
+        let mut inner = self.inner.lock().await;
+
+        if inner
+            .index
+            .get(&(session.username.clone(), session.id.clone()))
+            .is_none_or(|current| current != previous_token)
+        {
+            return Ok(false);
+        }
+
+        inner.write(session);
+
+        Ok(true)
+    }
+
+    async fn remove(&self, username: &str, id: &str) -> Result<Option<Session>> {
+        let mut inner = self.inner.lock().await;
+
+        Ok(inner
+            .index
+            .remove(&(username.to_string(), id.to_string()))
+            .and_then(|token| inner.tokens.remove(&token)))
+    }
+
+    async fn touch(
+        &self,
+        username: &str,
+        id: &str,
+        token: &str,
+        now: jiff::Timestamp,
+    ) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+
+        if inner
+            .index
+            .get(&(username.to_string(), id.to_string()))
+            .is_some_and(|current| current == token)
+            && let Some(session) = inner.tokens.get_mut(token)
+        {
+            session.last_seen = Some(now);
+        }
+
+        Ok(())
+    }
+
+    async fn get_refresh(&self, refresh_token: &str) -> Result<Option<RefreshToken>> {
+        Ok(self.inner.lock().await.refresh.get(refresh_token).cloned())
+    }
+
+    async fn remove_refresh(&self, refresh_token: &str) -> Result<()> {
+        self.inner.lock().await.refresh.remove(refresh_token);
+        Ok(())
+    }
+
+    async fn put_pending(&self, key: &str, data: &str, expires_on: jiff::Timestamp) -> Result<()> {
+        self.inner.lock().await.pending.insert(
+            key.to_string(),
+            Pending {
+                data: data.to_string(),
+                expires_on,
+            },
+        );
+        Ok(())
+    }
+
+    async fn take_pending(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .inner
+            .lock()
+            .await
+            .pending
+            .remove(key)
+            .filter(|pending| pending.expires_on > jiff::Timestamp::now())
+            .map(|pending| pending.data))
+    }
+
+    async fn migrate_plaintext_tokens(&self, _hash: &TokenHasher) -> Result<usize> {
+        Ok(0)
+    }
+
+    async fn purge_expired(&self) -> Result<usize> {
+        let mut inner = self.inner.lock().await;
+        let inner = &mut *inner;
+
+        let before = inner.tokens.len();
+        inner.tokens.retain(|_, session| session.is_expired().not());
+        inner
+            .index
+            .retain(|_, token| inner.tokens.contains_key(token));
+        inner.refresh.retain(|_, family| {
+            inner
+                .index
+                .contains_key(&(family.username.clone(), family.session.clone()))
+        });
+        let now = jiff::Timestamp::now();
+        inner.pending.retain(|_, pending| pending.expires_on >= now);
+
+        Ok(before - inner.tokens.len())
+    }
+}
+
+// Guardamos la sesion completa como json en `data`, los demas campos son solo
+// para poder buscarla. Igual que los usuarios se buscan por `username_key`
+pub struct Neo4jStore {
+    graph: neo4rs::Graph,
+}
+
+impl Neo4jStore {
+    pub fn new(graph: neo4rs::Graph) -> Self {
+        Neo4jStore { graph }
+    }
+
+    async fn fetch_sessions(&self, query: neo4rs::Query) -> Result<Vec<Session>> {
+        let mut stream = self.graph.execute(query).await?;
+
+        let mut sessions = vec![];
+        while let Some(row) = stream.next().await? {
+            let data: String = row.get("data").unwrap_or_default();
+            sessions.push(serde_json::from_str(&data)?);
+        }
+
+        Ok(sessions)
+    }
+}
+
+#[async_trait::async_trait]
+impl SessionStore for Neo4jStore {
+    async fn get(&self, token: &str) -> Result<Option<Session>> {
+        let sessions = self
+            .fetch_sessions(
+                neo4rs::Query::new(String::from(
+                    r#"
+                    MATCH (s:Session {token: $token})
+                    RETURN s.data AS data
+                    "#,
+                ))
+                .param("token", token),
+            )
+            .await?;
+
+        Ok(sessions.into_iter().next())
+    }
+
+    async fn get_by_id(&self, username: &str, id: &str) -> Result<Option<Session>> {
+        let sessions = self
+            .fetch_sessions(
+                neo4rs::Query::new(String::from(
+                    r#"
+                    MATCH (s:Session {username_key: $username_key, id: $id})
+                    RETURN s.data AS data
+                    "#,
+                ))
+                .param("username_key", lookup_key(username))
+                .param("id", id),
+            )
+            .await?;
+
+        Ok(sessions.into_iter().next())
+    }
+
+    async fn list(&self, username: &str) -> Result<Vec<Session>> {
+        self.fetch_sessions(
+            neo4rs::Query::new(String::from(
+                r#"
+                MATCH (s:Session {username_key: $username_key})
+                RETURN s.data AS data
+                "#,
+            ))
+            .param("username_key", lookup_key(username)),
+        )
+        .await
+    }
+
+    async fn insert(&self, session: &Session) -> Result<()> {
+        self.graph
+            .run(
+                neo4rs::Query::new(String::from(
+                    r#"
+                    MERGE (s:Session {username_key: $username_key, id: $id})
+                    SET s.username = $username,
+                        s.token = $token,
+                        s.data = $data,
+                        s.expires_on = $expires_on
+                    FOREACH (refresh IN CASE WHEN $refresh_token IS NULL THEN [] ELSE [$refresh_token] END |
+                        MERGE (r:RefreshToken {token: refresh})
+                        SET r.username = $username, r.session = $id
+                    )
+                    "#,
+                ))
+                .param("username", session.username.as_str())
+                .param("username_key", lookup_key(&session.username))
+                .param("id", session.id.as_str())
+                .param("token", session.token_hash.as_str())
+                .param("data", serde_json::to_string(session)?)
+                .param("expires_on", session.expires_on().as_second())
+                .param("refresh_token", session.refresh_token_hash.as_deref()),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn rotate(&self, previous_token: &str, session: &Session) -> Result<bool> {
+        // El primer SET toma el lock del nodo, el token se compara ya con el
+        // lock y no puede cambiar entre la comparacion y la escritura
+        let mut stream = self
+            .graph
+            .execute(
+                neo4rs::Query::new(String::from(
+                    r#"
+                    MATCH (s:Session {username_key: $username_key, id: $id})
+                    SET s._lock = true
+                    REMOVE s._lock
+                    WITH s
+                    WHERE s.token = $previous_token
+                    SET s.token = $token, s.data = $data, s.expires_on = $expires_on
+                    FOREACH (refresh IN CASE WHEN $refresh_token IS NULL THEN [] ELSE [$refresh_token] END |
+                        MERGE (r:RefreshToken {token: refresh})
+                        SET r.username = $username, r.session = $id
+                    )
+                    RETURN count(s) AS rotated
+                    "#,
+                ))
+                .param("username", session.username.as_str())
+                .param("username_key", lookup_key(&session.username))
+                .param("id", session.id.as_str())
+                .param("previous_token", previous_token)
+                .param("token", session.token_hash.as_str())
+                .param("data", serde_json::to_string(session)?)
+                .param("expires_on", session.expires_on().as_second())
+                .param("refresh_token", session.refresh_token_hash.as_deref()),
+            )
+            .await?;
+
+        let rotated: i64 = match stream.next().await? {
+            Some(row) => row.get("rotated").unwrap_or_default(),
+            None => 0,
+        };
+
+        Ok(rotated > 0)
+    }
+
+    async fn remove(&self, username: &str, id: &str) -> Result<Option<Session>> {
+        let sessions = self
+            .fetch_sessions(
+                neo4rs::Query::new(String::from(
+                    r#"
+                    MATCH (s:Session {username_key: $username_key, id: $id})
+                    WITH s, s.data AS data
+                    DELETE s
+                    RETURN data
+                    "#,
+                ))
+                .param("username_key", lookup_key(username))
+                .param("id", id),
+            )
+            .await?;
+
+        Ok(sessions.into_iter().next())
+    }
+
+    async fn touch(
+        &self,
+        username: &str,
+        id: &str,
+        token: &str,
+        now: jiff::Timestamp,
+    ) -> Result<()> {
+        let sessions = self
+            .fetch_sessions(
+                neo4rs::Query::new(String::from(
+                    r#"
+                    MATCH (s:Session {username_key: $username_key, id: $id, token: $token})
+                    RETURN s.data AS data
+                    "#,
+                ))
+                .param("username_key", lookup_key(username))
+                .param("id", id)
+                .param("token", token),
+            )
+            .await?;
+
+        let Some(mut session) = sessions.into_iter().next() else {
+            return Ok(());
+        };
+        session.last_seen = Some(now);
+
+        // Si entre las dos consultas se revoco o roto la sesion el MATCH ya
+        // no encuentra nada
+        self.graph
+            .run(
+                neo4rs::Query::new(String::from(
+                    r#"
+                    MATCH (s:Session {username_key: $username_key, id: $id, token: $token})
+                    SET s.data = $data
+                    "#,
+                ))
+                .param("username_key", lookup_key(username))
+                .param("id", id)
+                .param("token", token)
+                .param("data", serde_json::to_string(&session)?),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_refresh(&self, refresh_token: &str) -> Result<Option<RefreshToken>> {
+        let mut stream = self
+            .graph
+            .execute(
+                neo4rs::Query::new(String::from(
+                    r#"
+                    MATCH (r:RefreshToken {token: $token})
+                    RETURN r.username AS username, r.session AS session
+                    "#,
+                ))
+                .param("token", refresh_token),
+            )
+            .await?;
+
+        Ok(stream.next().await?.map(|row| RefreshToken {
+            username: row.get("username").unwrap_or_default(),
+            session: row.get("session").unwrap_or_default(),
+        }))
+    }
+
+    async fn remove_refresh(&self, refresh_token: &str) -> Result<()> {
+        self.graph
+            .run(
+                neo4rs::Query::new(String::from(
+                    r#"
+                    MATCH (r:RefreshToken {token: $token})
+                    DELETE r
+                    "#,
+                ))
+                .param("token", refresh_token),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn put_pending(&self, key: &str, data: &str, expires_on: jiff::Timestamp) -> Result<()> {
+        self.graph
+            .run(
+                neo4rs::Query::new(String::from(
+                    r#"
+                    MERGE (p:Pending {key: $key})
+                    SET p.data = $data, p.expires_on = $expires_on
+                    "#,
+                ))
+                .param("key", key)
+                .param("data", data)
+                .param("expires_on", expires_on.as_second()),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn take_pending(&self, key: &str) -> Result<Option<String>> {
+        let mut stream = self
+            .graph
+            .execute(
+                neo4rs::Query::new(String::from(
+                    r#"
+                    MATCH (p:Pending {key: $key})
+                    WITH p, p.data AS data, p.expires_on >= $now AS valid
+                    DELETE p
+                    RETURN data, valid
+                    "#,
+                ))
+                .param("key", key)
+                .param("now", jiff::Timestamp::now().as_second()),
+            )
+            .await?;
+
+        let Some(row) = stream.next().await? else {
+            return Ok(None);
+        };
+
+        Ok(row
+            .get::<bool>("valid")
+            .unwrap_or_default()
+            .then(|| row.get("data").unwrap_or_default()))
+    }
+
+    async fn purge_expired(&self) -> Result<usize> {
+        let mut stream = self
+            .graph
+            .execute(
+                neo4rs::Query::new(String::from(
+                    r#"
+                    MATCH (s:Session)
+                    WHERE s.expires_on < $now
+                    DELETE s
+                    RETURN count(s) AS purged
+                    "#,
+                ))
+                .param("now", jiff::Timestamp::now().as_second()),
+            )
+            .await?;
+
+        let purged: i64 = match stream.next().await? {
+            Some(row) => row.get("purged").unwrap_or_default(),
+            None => 0,
+        };
+
+        self.graph
+            .run(neo4rs::Query::new(String::from(
+                r#"
+                MATCH (r:RefreshToken)
+                WHERE NOT EXISTS {
+                    MATCH (:Session {username: r.username, id: r.session})
+                }
+                DELETE r
+                "#,
+            )))
+            .await?;
+
+        self.graph
+            .run(
+                neo4rs::Query::new(String::from(
+                    r#"
+                    MATCH (p:Pending)
+                    WHERE p.expires_on < $now
+                    DELETE p
+                    "#,
+                ))
+                .param("now", jiff::Timestamp::now().as_second()),
+            )
+            .await?;
+
+        Ok(purged as usize)
+    }
+
+    async fn migrate_plaintext_tokens(&self, hash: &TokenHasher) -> Result<usize> {
+        // Primero los refresh tokens, `insert` hace MERGE sobre el token ya
+        // hasheado y no debe chocar con el nodo viejo
+        let mut stream = self
+            .graph
+            .execute(neo4rs::Query::new(String::from(
+                r#"
+                MATCH (r:RefreshToken)
+                WHERE size(r.token) <> 64
+                RETURN r.token AS token
+                "#,
+            )))
+            .await?;
+
+        let mut legacy_refresh = vec![];
+        while let Some(row) = stream.next().await? {
+            legacy_refresh.push(row.get::<String>("token").unwrap_or_default());
+        }
+
+        for refresh_token in legacy_refresh {
+            self.graph
+                .run(
+                    neo4rs::Query::new(String::from(
+                        r#"
+                        MATCH (r:RefreshToken {token: $token})
+                        SET r.token = $token_hash
+                        "#,
+                    ))
+                    .param("token_hash", hash(&refresh_token))
+                    .param("token", refresh_token),
+                )
+                .await?;
+        }
+
+        let legacy = self
+            .fetch_sessions(neo4rs::Query::new(String::from(
+                r#"
+                MATCH (s:Session)
+                WHERE size(s.token) <> 64 OR coalesce(s.id, '') = ''
+                RETURN s.data AS data
+                "#,
+            )))
+            .await?;
+
+        // Buscamos cada nodo por su token, con `insert` un MERGE por
+        // `(username, id)` juntaria todas las sesiones sin id
+        for mut session in legacy.iter().cloned() {
+            let token = session.token_hash.clone();
+
+            if session.id.is_empty() {
+                session.id = generate_random_token::<16>();
+            }
+
+            if is_token_hash(token.as_bytes()).not() {
+                session.token_hash = hash(&session.token_hash);
+                session.refresh_token_hash = session
+                    .refresh_token_hash
+                    .as_deref()
+                    .map(|refresh_token| hash(refresh_token));
+            }
+
+            self.graph
+                .run(
+                    neo4rs::Query::new(String::from(
+                        r#"
+                        MATCH (s:Session {token: $token})
+                        SET s.id = $id,
+                            s.username_key = $username_key,
+                            s.token = $token_hash,
+                            s.data = $data
+                        FOREACH (refresh IN CASE WHEN $refresh_token IS NULL THEN [] ELSE [$refresh_token] END |
+                            MERGE (r:RefreshToken {token: refresh})
+                            SET r.username = $username, r.session = $id
+                        )
+                        "#,
+                    ))
+                    .param("token", token)
+                    .param("id", session.id.as_str())
+                    .param("username", session.username.as_str())
+                    .param("username_key", lookup_key(&session.username))
+                    .param("token_hash", session.token_hash.as_str())
+                    .param("data", serde_json::to_string(&session)?)
+                    .param("refresh_token", session.refresh_token_hash.as_deref()),
+                )
+                .await?;
+        }
+
+        // Las sesiones que ya tenian id pero son de antes de `username_key`.
+        // Si otra ya tiene la misma llave e id es un duplicado de los MERGE
+        // por `(username, id)` sin restriccion y se borra
+        let mut stream = self
+            .graph
+            .execute(neo4rs::Query::new(String::from(
+                r#"
+                MATCH (s:Session)
+                WHERE s.username_key IS NULL
+                RETURN elementId(s) AS node, s.username AS username
+                "#,
+            )))
+            .await?;
+
+        let mut unkeyed = vec![];
+        while let Some(row) = stream.next().await? {
+            unkeyed.push((
+                row.get::<String>("node").unwrap_or_default(),
+                row.get::<String>("username").unwrap_or_default(),
+            ));
+        }
+
+        for (node, username) in &unkeyed {
+            self.graph
+                .run(
+                    neo4rs::Query::new(String::from(
+                        r#"
+                        MATCH (s:Session)
+                        WHERE elementId(s) = $node
+                        OPTIONAL MATCH (other:Session {username_key: $username_key, id: s.id})
+                        WITH s, count(other) AS taken
+                        FOREACH (_ IN CASE WHEN taken = 0 THEN [1] ELSE [] END |
+                            SET s.username_key = $username_key
+                        )
+                        FOREACH (_ IN CASE WHEN taken > 0 THEN [1] ELSE [] END |
+                            DELETE s
+                        )
+                        "#,
+                    ))
+                    .param("node", node.as_str())
+                    .param("username_key", lookup_key(username)),
+                )
+                .await?;
+        }
+
+        Ok(legacy.len() + unkeyed.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(id: &str, token: &str, refresh_token: &str, created_ago: i64) -> Session {
+        let created_on = jiff::Timestamp::now() - jiff::SignedDuration::from_hours(created_ago);
+
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "token_hash": token,
+            "username": "maria",
+            "created_on": created_on,
+            "duration": jiff::SignedDuration::from_hours(1),
+            "refresh_token_hash": refresh_token,
+        }))
+        .expect("valid session")
+    }
+
+    // Cada prueba corre contra todos los stores que no necesitan un servidor
+    async fn each_store(check: impl AsyncFn(&dyn SessionStore)) {
+        check(&MemoryStore::default()).await;
+
+        let dir = tempfile::tempdir().expect("temporary directory");
+        let db = sled::open(dir.path()).expect("temporary sled db");
+        check(&SledStore::new(&db).expect("session trees")).await;
+    }
+
+    #[tokio::test]
+    async fn insert_replaces_the_token_of_the_same_session() {
+        each_store(async |store: &dyn SessionStore| {
+            store.insert(&session("a", "t1", "r1", 0)).await.unwrap();
+            store.insert(&session("a", "t2", "r2", 0)).await.unwrap();
+
+            assert!(store.get("t1").await.unwrap().is_none());
+            assert_eq!(store.get("t2").await.unwrap().unwrap().id, "a");
+            let current = store.get_by_id("maria", "a").await.unwrap().unwrap();
+            assert_eq!(current.token_hash, "t2");
+            assert_eq!(store.list("maria").await.unwrap().len(), 1);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn remove_forgets_the_session() {
+        each_store(async |store: &dyn SessionStore| {
+            store.insert(&session("a", "t1", "r1", 0)).await.unwrap();
+
+            let removed = store.remove("maria", "a").await.unwrap();
+
+            assert_eq!(removed.unwrap().token_hash, "t1");
+            assert!(store.get("t1").await.unwrap().is_none());
+            assert!(store.get_by_id("maria", "a").await.unwrap().is_none());
+            assert!(store.remove("maria", "a").await.unwrap().is_none());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_point_to_their_session() {
+        each_store(async |store: &dyn SessionStore| {
+            store.insert(&session("a", "t1", "r1", 0)).await.unwrap();
+            store.insert(&session("a", "t2", "r2", 0)).await.unwrap();
+
+            // El refresh anterior sigue apuntando a la familia para detectar reusos
+            for refresh_token in ["r1", "r2"] {
+                let family = store.get_refresh(refresh_token).await.unwrap().unwrap();
+                assert_eq!(family.username, "maria");
+                assert_eq!(family.session, "a");
+            }
+
+            store.remove_refresh("r1").await.unwrap();
+            assert!(store.get_refresh("r1").await.unwrap().is_none());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn rotate_only_replaces_the_expected_token() {
+        each_store(async |store: &dyn SessionStore| {
+            store.insert(&session("a", "t1", "r1", 0)).await.unwrap();
+
+            let first = store.rotate("t1", &session("a", "t2", "r2", 0)).await;
+            let second = store.rotate("t1", &session("a", "t3", "r3", 0)).await;
+
+            assert!(first.unwrap());
+            assert!(second.unwrap().not());
+            let current = store.get_by_id("maria", "a").await.unwrap().unwrap();
+            assert_eq!(current.token_hash, "t2");
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn touch_ignores_stale_tokens() {
+        each_store(async |store: &dyn SessionStore| {
+            let now = jiff::Timestamp::now();
+            store.insert(&session("a", "t1", "r1", 0)).await.unwrap();
+            store.insert(&session("a", "t2", "r2", 0)).await.unwrap();
+
+            store.touch("maria", "a", "t1", now).await.unwrap();
+            assert!(store.get("t2").await.unwrap().unwrap().last_seen.is_none());
+
+            store.touch("maria", "a", "t2", now).await.unwrap();
+            assert_eq!(store.get("t2").await.unwrap().unwrap().last_seen, Some(now));
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn pending_entries_are_taken_once_before_they_expire() {
+        each_store(async |store: &dyn SessionStore| {
+            let now = jiff::Timestamp::now();
+            let later = now + jiff::SignedDuration::from_mins(10);
+            let earlier = now - jiff::SignedDuration::from_mins(10);
+            store.put_pending("fresh", "data", later).await.unwrap();
+            store.put_pending("stale", "data", earlier).await.unwrap();
+
+            assert_eq!(
+                store.take_pending("fresh").await.unwrap().as_deref(),
+                Some("data")
+            );
+            assert!(store.take_pending("fresh").await.unwrap().is_none());
+            assert!(store.take_pending("stale").await.unwrap().is_none());
+            assert!(store.take_pending("missing").await.unwrap().is_none());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn purge_expired_drops_sessions_and_their_refresh_tokens() {
+        each_store(async |store: &dyn SessionStore| {
+            store.insert(&session("old", "t1", "r1", 2)).await.unwrap();
+            store.insert(&session("new", "t2", "r2", 0)).await.unwrap();
+
+            assert_eq!(store.purge_expired().await.unwrap(), 1);
+
+            assert!(store.get("t1").await.unwrap().is_none());
+            assert!(store.get_by_id("maria", "old").await.unwrap().is_none());
+            assert!(store.get_refresh("r1").await.unwrap().is_none());
+            assert!(store.get("t2").await.unwrap().is_some());
+            assert!(store.get_refresh("r2").await.unwrap().is_some());
+            assert_eq!(store.purge_expired().await.unwrap(), 0);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn sled_purge_covers_every_batch() {
+        let dir = tempfile::tempdir().expect("temporary directory");
+        let db = sled::open(dir.path()).expect("temporary sled db");
+        let store = SledStore::new(&db).expect("session trees");
+
+        let expired = 2 * PURGE_BATCH + 1;
+        for n in 0..expired {
+            let old = session(&format!("old{n}"), &format!("t{n}"), &format!("r{n}"), 2);
+            store.insert(&old).await.unwrap();
+        }
+        store.insert(&session("new", "tn", "rn", 0)).await.unwrap();
+
+        assert_eq!(store.purge_expired().await.unwrap(), expired);
+        assert_eq!(store.list("maria").await.unwrap().len(), 1);
+        assert!(store.get_refresh("r0").await.unwrap().is_none());
+        assert!(store.get_refresh("rn").await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,236 @@
+use std::net::IpAddr;
+
+use axum::{
+    http,
+    response::{IntoResponse, Response},
+};
+use jiff::{SignedDuration, Timestamp};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{args, auth};
+
+#[derive(Debug)]
+pub enum Error {
+    Sled(std::io::Error),
+    Serde(serde_json::Error),
+}
+
+impl From<Error> for http::StatusCode {
+    fn from(value: Error) -> Self {
+        match value {
+            Error::Sled(err) => tracing::error!("Failed accessing login attempts tree {err:?}"),
+            Error::Serde(err) => tracing::error!("Failed (de)serializing login attempts {err:?}"),
+        }
+
+        http::StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Sled(err)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Serde(err)
+    }
+}
+
+// Respuesta para quien sigue bloqueado, el cliente puede reintentar despues de
+// `Retry-After` segundos
+pub struct Locked(pub SignedDuration);
+
+impl IntoResponse for Locked {
+    fn into_response(self) -> Response {
+        let Locked(remaining) = self;
+        // Redondeamos hacia arriba para no invitar a reintentar antes de tiempo
+        let seconds = remaining.as_secs() + i64::from(remaining.subsec_nanos() > 0);
+
+        (
+            http::StatusCode::TOO_MANY_REQUESTS,
+            [(http::header::RETRY_AFTER, seconds.max(1).to_string())],
+        )
+            .into_response()
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Attempts {
+    failures: u32,
+    last_failure: Option<Timestamp>,
+    locked_until: Option<Timestamp>,
+}
+
+// Cuenta los intentos fallidos de inicio de sesion por usuario y por ip. Con
+// cada fallo despues del limite el bloqueo dura el doble, hasta el maximo
+pub struct LoginThrottle {
+    attempts: Mutex<sled::Tree<1024>>,
+    policy: args::Throttle,
+}
+
+impl LoginThrottle {
+    pub fn new(db: &sled::Db<1024>, policy: args::Throttle) -> std::io::Result<Self> {
+        Ok(LoginThrottle {
+            attempts: Mutex::new(db.open_tree("login_attempts")?),
+            policy,
+        })
+    }
+
+    // Regresa cuanto falta para que termine el bloqueo mas largo entre el
+    // usuario y la ip
+    pub async fn check(
+        &self,
+        username: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<Option<SignedDuration>, Error> {
+        let attempts = self.attempts.lock().await;
+        let now = Timestamp::now();
+
+        let mut remaining = None;
+        for key in [Some(user_key(username)), ip.map(ip_key)]
+            .into_iter()
+            .flatten()
+        {
+            let Some(locked_until) = read_attempts(&attempts, &key)?.locked_until else {
+                continue;
+            };
+
+            if locked_until > now {
+                let left = now.duration_until(locked_until);
+                remaining = Some(remaining.map_or(left, |other: SignedDuration| other.max(left)));
+            }
+        }
+
+        Ok(remaining)
+    }
+
+    pub async fn record_failure(&self, username: &str, ip: Option<IpAddr>) -> Result<(), Error> {
+        let attempts = self.attempts.lock().await;
+        let now = Timestamp::now();
+
+        let limits = [
+            (
+                Some(user_key(username)),
+                self.policy.login_max_user_failures,
+            ),
+            (ip.map(ip_key), self.policy.login_max_ip_failures),
+        ];
+
+        for (key, limit) in limits {
+            let Some(key) = key else {
+                continue;
+            };
+
+            let mut entry = read_attempts(&attempts, &key)?;
+
+            // Si paso la ventana desde el ultimo fallo (o desde que termino el
+            // bloqueo) volvemos a empezar
+            let since = entry.locked_until.or(entry.last_failure);
+            if since.is_some_and(|since| since + self.policy.login_failure_window < now) {
+                entry = Attempts::default();
+            }
+
+            entry.failures += 1;
+            entry.last_failure = Some(now);
+
+            if entry.failures >= limit {
+                let lockout = lockout_for(&self.policy, entry.failures - limit);
+                entry.locked_until = Some(now + lockout);
+
+                tracing::warn!(
+                    "Locking out {key} for {lockout} after {failures} failed signins",
+                    key = String::from_utf8_lossy(&key),
+                    failures = entry.failures
+                );
+            }
+
+            attempts.insert(&key, serde_json::to_vec(&entry)?)?;
+        }
+
+        Ok(())
+    }
+
+    // Un inicio de sesion correcto limpia los fallos del usuario, los de la ip
+    // se quedan para no regalarle intentos a quien prueba muchas cuentas
+    pub async fn reset(&self, username: &str) -> Result<(), Error> {
+        let attempts = self.attempts.lock().await;
+        attempts.remove(user_key(username))?;
+        Ok(())
+    }
+}
+
+fn lockout_for(policy: &args::Throttle, over_limit: u32) -> SignedDuration {
+    // Con 16 dobleces cualquier base razonable ya paso el maximo
+    let factor = 1i32 << over_limit.min(16);
+
+    policy
+        .login_lockout_base
+        .checked_mul(factor)
+        .unwrap_or(policy.login_lockout_max)
+        .min(policy.login_lockout_max)
+}
+
+fn user_key(username: &str) -> Vec<u8> {
+    format!("user:{key}", key = auth::lookup_key(username)).into_bytes()
+}
+
+fn ip_key(ip: IpAddr) -> Vec<u8> {
+    format!("ip:{ip}").into_bytes()
+}
+
+fn read_attempts(tree: &sled::Tree<1024>, key: &[u8]) -> Result<Attempts, Error> {
+    match tree.get(key)? {
+        Some(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        None => Ok(Attempts::default()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> args::Throttle {
+        args::Throttle {
+            login_max_user_failures: 5,
+            login_max_ip_failures: 20,
+            login_lockout_base: SignedDuration::from_secs(30),
+            login_lockout_max: SignedDuration::from_hours(1),
+            login_failure_window: SignedDuration::from_hours(1),
+        }
+    }
+
+    fn retry_after(remaining: SignedDuration) -> String {
+        let response = Locked(remaining).into_response();
+        assert_eq!(response.status(), http::StatusCode::TOO_MANY_REQUESTS);
+
+        response.headers()[http::header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn lockout_doubles_up_to_the_max() {
+        let policy = policy();
+
+        assert_eq!(lockout_for(&policy, 0), SignedDuration::from_secs(30));
+        assert_eq!(lockout_for(&policy, 1), SignedDuration::from_secs(60));
+        assert_eq!(lockout_for(&policy, 3), SignedDuration::from_secs(240));
+        assert_eq!(lockout_for(&policy, 7), SignedDuration::from_hours(1));
+        assert_eq!(
+            lockout_for(&policy, u32::MAX),
+            SignedDuration::from_hours(1)
+        );
+    }
+
+    #[test]
+    fn retry_after_rounds_up() {
+        assert_eq!(retry_after(SignedDuration::from_secs(30)), "30");
+        assert_eq!(retry_after(SignedDuration::from_millis(30_001)), "31");
+        assert_eq!(retry_after(SignedDuration::from_millis(200)), "1");
+        assert_eq!(retry_after(SignedDuration::ZERO), "1");
+    }
+}
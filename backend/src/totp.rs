@@ -0,0 +1,628 @@
+use std::ops::Not;
+
+use axum::{
+    body::Bytes,
+    extract::State,
+    http,
+    response::{IntoResponse, Response},
+};
+use facet::Facet;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::{
+    Ctx, audit,
+    auth::{self, ClientInfo, Session},
+    json::Json,
+    neo4j, throttle,
+};
+
+const ISSUER: &str = "Orbitly";
+const RECOVERY_CODES: usize = 10;
+
+// Lo que regresa el primer paso del login cuando el usuario tiene TOTP, el
+// challenge se cambia en `/auth/signin/totp` junto con un codigo
+#[derive(serde::Serialize)]
+pub struct Challenge {
+    pub challenge: String,
+    pub expires_in: i64,
+}
+
+#[derive(serde::Serialize)]
+pub struct Enrollment {
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(serde::Serialize)]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Facet, Clone, Copy)]
+struct ConfirmReq<'inp> {
+    code: &'inp str,
+}
+
+#[derive(Facet, Clone, Copy)]
+struct EnrollReq<'inp> {
+    #[facet(default)]
+    password: Option<&'inp str>,
+}
+
+// Sirve un codigo TOTP o uno de recuperacion, asi tambien lo pueden
+// desactivar las cuentas sin contraseña
+#[derive(Facet, Clone, Copy)]
+struct DisableReq<'inp> {
+    code: &'inp str,
+}
+
+#[derive(Facet, Clone, Copy)]
+struct SigninTotpReq<'inp> {
+    challenge: &'inp str,
+    code: &'inp str,
+}
+
+fn totp_for(secret: &str, username: &str) -> Result<TOTP, http::StatusCode> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|err| {
+            tracing::error!("Stored TOTP secret for {username} is not base32 {err:?}");
+            http::StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(TOTP::new_unchecked(
+        Algorithm::SHA1,
+        6,
+        1,
+        30,
+        secret,
+        Some(String::from(ISSUER)),
+        username.to_string(),
+    ))
+}
+
+// Los codigos se suelen mostrar en grupos, ignoramos los espacios
+fn normalize_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_whitespace().not()).collect()
+}
+
+// Como `check_current` pero regresa el paso de tiempo que coincidio, para no
+// aceptar el mismo codigo dos veces
+fn matching_step(totp: &TOTP, code: &str) -> Option<i64> {
+    let now = jiff::Timestamp::now().as_second().max(0) as u64;
+    let current = now / totp.step;
+    let skew = u64::from(totp.skew);
+
+    (current.saturating_sub(skew)..=current + skew)
+        .find(|step| totp.generate(step * totp.step) == code)
+        .map(|step| step as i64)
+}
+
+// Guarda `step` como el ultimo usado si es mas nuevo que el anterior. Si no lo
+// es el codigo ya se uso o es de antes de uno que ya se uso
+async fn claim_step(ctx: &Ctx, username: &str, step: i64) -> Result<bool, Response> {
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                })
+                WHERE coalesce(u.totp_last_step, -1) < $step
+                SET u.totp_last_step = $step
+                RETURN u.username AS username"#,
+            ))
+            .param("username_key", auth::lookup_key(username))
+            .param("step", step),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    Ok(row.is_some())
+}
+
+pub async fn enroll_totp(
+    State(ctx): State<Ctx>,
+    client: ClientInfo,
+    session: Session,
+    bytes: Bytes,
+) -> Result<axum::Json<Enrollment>, Response> {
+    // Las cuentas sin contraseña pueden mandar el cuerpo vacio
+    let req = if bytes.is_empty() {
+        EnrollReq { password: None }
+    } else {
+        let Json(req): Json<EnrollReq> =
+            Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+        req
+    };
+
+    // Con una sesion robada no se puede amarrar la cuenta a otro telefono
+    if auth::reauthenticate(&ctx, &session, &client, req.password)
+        .await?
+        .not()
+    {
+        Err(http::StatusCode::FORBIDDEN.into_response())?
+    }
+
+    let secret = Secret::generate_secret().to_encoded().to_string();
+
+    // Mientras no se confirme el secreto queda pendiente y el login no cambia
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                })
+                WHERE u.totp_secret IS NULL
+                SET u.totp_pending = $secret
+                RETURN u.username AS username"#,
+            ))
+            .param("username_key", auth::lookup_key(&session.username))
+            .param("secret", secret.as_str()),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    if row.is_none() {
+        Err(http::StatusCode::CONFLICT.into_response())?
+    }
+
+    let totp = totp_for(&secret, &session.username).map_err(|res| res.into_response())?;
+
+    Ok(axum::Json(Enrollment {
+        secret: totp.get_secret_base32(),
+        otpauth_uri: totp.get_url(),
+    }))
+}
+
+pub async fn confirm_totp(
+    State(ctx): State<Ctx>,
+    client: ClientInfo,
+    session: Session,
+    bytes: Bytes,
+) -> Result<axum::Json<RecoveryCodes>, Response> {
+    let json @ Json(req): Json<ConfirmReq> =
+        Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+
+    if json.is_all_str_set().not() {
+        Err((http::StatusCode::BAD_REQUEST).into_response())?;
+    }
+
+    let mut stream = ctx
+        .neo4j
+        .execute_read(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                }) RETURN u.totp_pending AS pending"#,
+            ))
+            .param("username_key", auth::lookup_key(&session.username)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let Some(pending) = row.and_then(|row| row.get::<String>("pending").ok()) else {
+        Err(http::StatusCode::CONFLICT.into_response())?
+    };
+
+    let totp = totp_for(&pending, &session.username).map_err(|res| res.into_response())?;
+    let Some(step) = matching_step(&totp, &normalize_code(req.code)) else {
+        Err(http::StatusCode::FORBIDDEN.into_response())?
+    };
+
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODES)
+        .map(|_| auth::generate_random_token::<10>())
+        .collect();
+    let recovery_hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| auth::hash_token(&ctx, code))
+        .collect();
+
+    // Si alguien volvio a enrolar mientras tanto el secreto pendiente ya es
+    // otro y no activamos nada
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key,
+                    totp_pending: $secret
+                })
+                SET u.totp_secret = u.totp_pending,
+                    u.totp_recovery = $recovery,
+                    u.totp_last_step = $step
+                REMOVE u.totp_pending
+                RETURN u.username AS username"#,
+            ))
+            .param("username_key", auth::lookup_key(&session.username))
+            .param("secret", pending.as_str())
+            .param("step", step)
+            .param("recovery", recovery_hashes),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    if row.is_none() {
+        Err(http::StatusCode::CONFLICT.into_response())?
+    }
+
+    ctx.audit
+        .record(audit::Event::new(
+            audit::Kind::TotpEnabled,
+            audit::Outcome::Success,
+            &session.username,
+            &client,
+        ))
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    tracing::debug!("TOTP enabled for {username}", username = session.username);
+
+    Ok(axum::Json(RecoveryCodes { recovery_codes }))
+}
+
+pub async fn disable_totp(
+    State(ctx): State<Ctx>,
+    client: ClientInfo,
+    session: Session,
+    bytes: Bytes,
+) -> Result<http::StatusCode, Response> {
+    let json @ Json(req): Json<DisableReq> =
+        Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+
+    if json.is_all_str_set().not() {
+        Err((http::StatusCode::BAD_REQUEST).into_response())?;
+    }
+
+    let mut stream = ctx
+        .neo4j
+        .execute_read(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                }) RETURN u.totp_secret AS secret"#,
+            ))
+            .param("username_key", auth::lookup_key(&session.username)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let Some(secret) = row.and_then(|row| row.get::<String>("secret").ok()) else {
+        Err(http::StatusCode::CONFLICT.into_response())?
+    };
+
+    // Los codigos equivocados cuentan igual que en `signin_totp`
+    if let Some(remaining) = ctx
+        .throttle
+        .check(&session.username, client.ip)
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+    {
+        Err(throttle::Locked(remaining).into_response())?
+    }
+
+    if verify_code(&ctx, &session.username, &secret, req.code)
+        .await?
+        .not()
+    {
+        ctx.throttle
+            .record_failure(&session.username, client.ip)
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        ctx.audit
+            .record(audit::Event::new(
+                audit::Kind::TotpDisabled,
+                audit::Outcome::Failure,
+                &session.username,
+                &client,
+            ))
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        Err(http::StatusCode::FORBIDDEN.into_response())?
+    }
+
+    ctx.neo4j
+        .run(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                })
+                REMOVE u.totp_secret, u.totp_pending, u.totp_recovery, u.totp_last_step,
+                    u.totp_challenge, u.totp_challenge_expires_on"#,
+            ))
+            .param("username_key", auth::lookup_key(&session.username)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    ctx.audit
+        .record(audit::Event::new(
+            audit::Kind::TotpDisabled,
+            audit::Outcome::Success,
+            &session.username,
+            &client,
+        ))
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    tracing::debug!("TOTP disabled for {username}", username = session.username);
+
+    Ok(http::StatusCode::NO_CONTENT)
+}
+
+// Si el usuario tiene TOTP guarda un challenge nuevo y lo regresa, el login
+// solo termina cuando se presenta junto con un codigo
+pub async fn start_challenge(ctx: &Ctx, username: &str) -> Result<Option<Challenge>, Response> {
+    let challenge = auth::generate_random_token::<50>();
+    let expires_on = jiff::Timestamp::now()
+        .checked_add(ctx.auth.totp_challenge_ttl)
+        .expect("in overflows we do not believe");
+
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                })
+                WHERE u.totp_secret IS NOT NULL
+                SET u.totp_challenge = $challenge_hash,
+                    u.totp_challenge_expires_on = $expires_on
+                RETURN u.username AS username"#,
+            ))
+            .param("username_key", auth::lookup_key(username))
+            .param("challenge_hash", auth::hash_token(ctx, &challenge))
+            .param("expires_on", expires_on.as_second()),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    Ok(row.map(|_| Challenge {
+        challenge,
+        expires_in: ctx.auth.totp_challenge_ttl.as_secs(),
+    }))
+}
+
+pub async fn signin_totp(
+    State(ctx): State<Ctx>,
+    client: ClientInfo,
+    bytes: Bytes,
+) -> Result<Response, Response> {
+    let json @ Json(req): Json<SigninTotpReq> =
+        Json::from_bytes(&bytes).map_err(|err| err.into_response())?;
+
+    if json.is_all_str_set().not() {
+        Err((http::StatusCode::BAD_REQUEST).into_response())?;
+    }
+
+    // El challenge se consume antes de revisar el codigo, asi dos requests
+    // con el mismo challenge no gastan dos codigos de recuperacion. Un codigo
+    // equivocado obliga a volver a poner la contraseña
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    totp_challenge: $challenge_hash
+                })
+                SET u._lock = true
+                REMOVE u._lock
+                WITH u
+                WHERE u.totp_challenge = $challenge_hash
+                WITH u, u.totp_challenge_expires_on >= $now AS valid
+                REMOVE u.totp_challenge, u.totp_challenge_expires_on
+                RETURN u.username AS username,
+                    u.totp_secret AS secret,
+                    valid"#,
+            ))
+            .param("challenge_hash", auth::hash_token(&ctx, req.challenge))
+            .param("now", jiff::Timestamp::now().as_second()),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let Some(row) = row else {
+        Err(http::StatusCode::UNAUTHORIZED.into_response())?
+    };
+
+    if row.get::<bool>("valid").unwrap_or_default().not() {
+        Err(http::StatusCode::UNAUTHORIZED.into_response())?
+    }
+
+    let username: String = row.get("username").unwrap_or_default();
+    let secret: String = row.get("secret").unwrap_or_default();
+
+    // Los codigos equivocados cuentan igual que una contraseña equivocada
+    if let Some(remaining) = ctx
+        .throttle
+        .check(&username, client.ip)
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?
+    {
+        ctx.audit
+            .record(audit::Event::new(
+                audit::Kind::Signin,
+                audit::Outcome::Locked,
+                &username,
+                &client,
+            ))
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        Err(throttle::Locked(remaining).into_response())?
+    }
+
+    if verify_code(&ctx, &username, &secret, req.code).await?.not() {
+        ctx.throttle
+            .record_failure(&username, client.ip)
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        ctx.audit
+            .record(audit::Event::new(
+                audit::Kind::Signin,
+                audit::Outcome::Failure,
+                &username,
+                &client,
+            ))
+            .await
+            .map_err(http::StatusCode::from)
+            .map_err(|res| res.into_response())?;
+
+        Err(http::StatusCode::UNAUTHORIZED.into_response())?
+    }
+
+    ctx.throttle
+        .reset(&username)
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let event = audit::Event::new(
+        audit::Kind::Signin,
+        audit::Outcome::Success,
+        &username,
+        &client,
+    );
+    let token = auth::issue_session(&ctx, &username, client)
+        .await
+        .map_err(|res| res.into_response())?;
+
+    ctx.audit
+        .record(event)
+        .await
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    Ok(auth::token_response(&ctx, token))
+}
+
+// Acepta un codigo TOTP que no se haya usado o uno de recuperacion
+async fn verify_code(
+    ctx: &Ctx,
+    username: &str,
+    secret: &str,
+    code: &str,
+) -> Result<bool, Response> {
+    let code = normalize_code(code);
+    let totp = totp_for(secret, username).map_err(|res| res.into_response())?;
+
+    match matching_step(&totp, &code) {
+        Some(step) => claim_step(ctx, username, step).await,
+        None => use_recovery_code(ctx, username, &code).await,
+    }
+}
+
+// Cada codigo de recuperacion sirve una sola vez
+async fn use_recovery_code(ctx: &Ctx, username: &str, code: &str) -> Result<bool, Response> {
+    let mut stream = ctx
+        .neo4j
+        .execute(
+            neo4rs::Query::new(String::from(
+                r#"MATCH (u:User {
+                    username_key: $username_key
+                })
+                WHERE $code_hash IN u.totp_recovery
+                SET u.totp_recovery = [c IN u.totp_recovery WHERE c <> $code_hash]
+                RETURN size(u.totp_recovery) AS remaining"#,
+            ))
+            .param("username_key", auth::lookup_key(username))
+            .param("code_hash", auth::hash_token(ctx, code)),
+        )
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let row = stream
+        .next()
+        .await
+        .map_err(neo4j::Error::from)
+        .map_err(http::StatusCode::from)
+        .map_err(|res| res.into_response())?;
+
+    let Some(row) = row else {
+        return Ok(false);
+    };
+
+    let remaining: i64 = row.get("remaining").unwrap_or_default();
+    tracing::info!("{username} signed in with a recovery code, {remaining} left");
+
+    Ok(true)
+}
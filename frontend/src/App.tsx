@@ -7,6 +7,7 @@ import Chat from "./pages/Chat";
 import Profile from "./pages/Profile";
 import Interests from "./pages/Interests";
 import Matches from "./pages/Matches";
+import Oidc from "./pages/Oidc";
 
 function App() {
   return (
@@ -19,6 +20,7 @@ function App() {
         <Route path="/profile/:userId" element={<Profile />} />
         <Route path="/interests" element={<Interests />} />
         <Route path="/matches" element={<Matches />} />
+        <Route path="/oidc" element={<Oidc />} />
       </Routes>
     </MobileFrame>
   );
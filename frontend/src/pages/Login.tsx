@@ -67,7 +67,7 @@ export default function Login() {
                   htmlFor="usuario"
                   className="block text-sm font-medium text-gray-100"
                 >
-                  Usuario
+                  Usuario o correo
                 </label>
                 <div className="mt-2">
                   <input
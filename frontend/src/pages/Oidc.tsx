@@ -0,0 +1,123 @@
+import { useEffect, useState } from "react";
+import { useNavigate } from "react-router-dom";
+import { authService } from "../services/authService";
+
+// A esta pagina regresa el backend despues del provider de OIDC. Los tokens o
+// el challenge de TOTP vienen en el fragmento para no quedar en ningun log
+export default function Oidc() {
+  const navigate = useNavigate();
+
+  const [params] = useState(() => new URLSearchParams(window.location.hash.slice(1)));
+  const challenge = params.get("challenge");
+
+  const [code, setCode] = useState<string>("");
+  const [error, setError] = useState<string>("");
+  const [loading, setLoading] = useState<boolean>(false);
+
+  useEffect(() => {
+    // Sacamos los tokens de la barra de direcciones y del historial
+    window.history.replaceState(null, "", window.location.pathname);
+
+    if (challenge) {
+      return;
+    }
+
+    if (authService.completeOidc(params)) {
+      navigate("/feed", { replace: true });
+    } else {
+      setError("No se pudo iniciar sesión con el proveedor");
+    }
+  }, [challenge, params, navigate]);
+
+  async function handleCode(e: React.FormEvent) {
+    e.preventDefault();
+    setError("");
+
+    if (!challenge || !code) {
+      setError("Escribe el código");
+      return;
+    }
+
+    setLoading(true);
+
+    try {
+      await authService.signinTotp(challenge, code);
+      navigate("/feed", { replace: true });
+    } catch (err: any) {
+      setError(err.message || "Error al iniciar sesión");
+    } finally {
+      setLoading(false);
+    }
+  }
+
+  return (
+      <div className="bg-gray-800 w-full h-full p-6">
+
+        <div className="flex min-h-full flex-col justify-center px-6 py-6">
+
+          <h2 className="text-center text-2xl font-bold tracking-tight text-white">
+            {challenge ? "Verificación en dos pasos" : "Iniciando sesión..."}
+          </h2>
+
+          <div className="mt-10 sm:mx-auto sm:w-full sm:max-w-sm">
+            {error && <p className="text-red-400 text-center mb-4">{error}</p>}
+
+            {challenge && (
+              <form onSubmit={handleCode} className="space-y-6">
+                <div>
+                  <label
+                    htmlFor="code"
+                    className="block text-sm font-medium text-gray-100"
+                  >
+                    Código de tu app o de recuperación
+                  </label>
+                  <div className="mt-2">
+                    <input
+                      id="code"
+                      type="text"
+                      inputMode="numeric"
+                      autoComplete="one-time-code"
+                      value={code}
+                      onChange={(e) => setCode(e.target.value)}
+                      disabled={loading}
+                      required
+                      className="block w-full rounded-md bg-white/5 px-3 py-1.5 
+                      text-base text-white outline-1 outline-white/10 
+                      placeholder:text-gray-500 focus:outline-2 
+                      focus:outline-indigo-500 disabled:opacity-50"
+                    />
+                  </div>
+                </div>
+
+                <div>
+                  <button
+                    type="submit"
+                    disabled={loading}
+                    className="flex w-full justify-center rounded-md bg-indigo-500 
+                    px-3 py-1.5 text-sm font-semibold text-white 
+                    hover:bg-indigo-400 focus-visible:outline-2 
+                    focus-visible:outline-indigo-500 disabled:opacity-50 
+                    disabled:cursor-not-allowed"
+                  >
+                    {loading ? "Cargando..." : "Verificar"}
+                  </button>
+                </div>
+              </form>
+            )}
+
+            {error && !challenge && (
+              <p className="mt-10 text-center text-sm text-gray-400">
+                <a
+                  href="/"
+                  className="font-semibold text-indigo-400 hover:text-indigo-300"
+                >
+                  Volver a iniciar sesión
+                </a>
+              </p>
+            )}
+          </div>
+        </div>
+
+      </div>
+  );
+}
@@ -1,7 +1,5 @@
 import { authService } from './authService';
 
-const API_URL = 'https://min.oxlo.io:6232';
-
 interface MatchUser {
   username: string;
   first_name?: string;
@@ -29,12 +27,9 @@ interface SearchResult {
 export const apiService = {
   // Obtener tus matches
   async getMyMatches(): Promise<MatchUser[]> {
-    const token = authService.getToken();
-    
-    const response = await fetch(`${API_URL}/me/matches`, {
+    const response = await authService.fetch(`/me/matches`, {
       method: 'GET',
       headers: {
-        'Authorization': `Bearer ${token}`,
         'Content-Type': 'application/json',
       },
     });
@@ -50,12 +45,9 @@ export const apiService = {
 
   // Obtener sugeridos (nivel 2)
   async getSuggested(): Promise<MatchUser[]> {
-    const token = authService.getToken();
-    
-    const response = await fetch(`${API_URL}/me/lv2`, {
+    const response = await authService.fetch(`/me/lv2`, {
       method: 'GET',
       headers: {
-        'Authorization': `Bearer ${token}`,
         'Content-Type': 'application/json',
       },
     });
@@ -70,12 +62,9 @@ export const apiService = {
 
   // Dar match con alguien
   async createMatch(targetUsername: string): Promise<void> {
-    const token = authService.getToken();
-    
-    const response = await fetch(`${API_URL}/me/match`, {
+    const response = await authService.fetch(`/me/match`, {
       method: 'POST',
       headers: {
-        'Authorization': `Bearer ${token}`,
         'Content-Type': 'application/json',
       },
       body: JSON.stringify({ target: targetUsername }),
@@ -88,12 +77,9 @@ export const apiService = {
 
   // Quitar match con alguien
   async deleteMatch(targetUsername: string): Promise<void> {
-    const token = authService.getToken();
-    
-    const response = await fetch(`${API_URL}/me/match`, {
+    const response = await authService.fetch(`/me/match`, {
       method: 'DELETE',
       headers: {
-        'Authorization': `Bearer ${token}`,
         'Content-Type': 'application/json',
       },
       body: JSON.stringify({ target: targetUsername }),
@@ -106,12 +92,9 @@ export const apiService = {
 
 // Obtener matches de otro usuario
 async getOtherMatches(username: string): Promise<MatchUser[]> {
-  const token = authService.getToken();
-  
-  const response = await fetch(`${API_URL}/other/matches`, {
+  const response = await authService.fetch(`/other/matches`, {
     method: 'POST',
     headers: {
-      'Authorization': `Bearer ${token}`,
       'Content-Type': 'application/json',
     },
     body: JSON.stringify({ username }),
@@ -127,12 +110,9 @@ async getOtherMatches(username: string): Promise<MatchUser[]> {
 
   // Obtener tus intereses/gustos
   async getMyInterests(): Promise<Interest[]> {
-    const token = authService.getToken();
-    
-    const response = await fetch(`${API_URL}/me/interest`, {
+    const response = await authService.fetch(`/me/interest`, {
       method: 'GET',
       headers: {
-        'Authorization': `Bearer ${token}`,
         'Content-Type': 'application/json',
       },
     });
@@ -147,12 +127,9 @@ async getOtherMatches(username: string): Promise<MatchUser[]> {
 
   // Dejar de seguir un interés
   async unlikeInterest(interestName: string): Promise<void> {
-    const token = authService.getToken();
-    
-    const response = await fetch(`${API_URL}/me/interest`, {
+    const response = await authService.fetch(`/me/interest`, {
       method: 'DELETE',
       headers: {
-        'Authorization': `Bearer ${token}`,
         'Content-Type': 'application/json',
       },
       body: JSON.stringify({ name: interestName }),
@@ -165,12 +142,9 @@ async getOtherMatches(username: string): Promise<MatchUser[]> {
 
 // Obtener intereses de otro usuario
 async getOtherInterests(username: string): Promise<Interest[]> {
-  const token = authService.getToken();
-  
-  const response = await fetch(`${API_URL}/other/interest`, {
+  const response = await authService.fetch(`/other/interest`, {
     method: 'POST',
     headers: {
-      'Authorization': `Bearer ${token}`,
       'Content-Type': 'application/json',
     },
     body: JSON.stringify({ username }),
@@ -186,12 +160,9 @@ async getOtherInterests(username: string): Promise<Interest[]> {
 
 // Buscar usuarios
 async searchUsers(term: string): Promise<SearchResult[]> {
-  const token = authService.getToken();
-  
-  const response = await fetch(`${API_URL}/other/search`, {
+  const response = await authService.fetch(`/other/search`, {
     method: 'POST',
     headers: {
-      'Authorization': `Bearer ${token}`,
       'Content-Type': 'application/json',
     },
     body: JSON.stringify({ term }),
@@ -209,12 +180,9 @@ async searchUsers(term: string): Promise<SearchResult[]> {
     // Obtener recomendaciones de intereses
 // Obtener recomendaciones de intereses
 async getRecommendedInterests(): Promise<Interest[]> {
-  const token = authService.getToken();
-  
-  const response = await fetch(`${API_URL}/me/recommendations`, {
+  const response = await authService.fetch(`/me/recommendations`, {
     method: 'GET',
     headers: {
-      'Authorization': `Bearer ${token}`,
       'Content-Type': 'application/json',
     },
   });
@@ -230,12 +198,9 @@ async getRecommendedInterests(): Promise<Interest[]> {
 
     // Agregar un interés
     async likeInterest(interestName: string): Promise<void> {
-    const token = authService.getToken();
-    
-    const response = await fetch(`${API_URL}/me/interest`, {
+    const response = await authService.fetch(`/me/interest`, {
         method: 'POST',
         headers: {
-        'Authorization': `Bearer ${token}`,
         'Content-Type': 'application/json',
         },
         body: JSON.stringify({ name: interestName }),
@@ -248,12 +213,9 @@ async getRecommendedInterests(): Promise<Interest[]> {
 
 // Obtener información de un usuario
 async getUserInfo(username: string): Promise<any> {
-  const token = authService.getToken();
-  
-  const response = await fetch(`${API_URL}/other`, {
+  const response = await authService.fetch(`/other`, {
     method: 'POST',
     headers: {
-      'Authorization': `Bearer ${token}`,
       'Content-Type': 'application/json',
     },
     body: JSON.stringify({ username }),
@@ -268,12 +230,9 @@ async getUserInfo(username: string): Promise<any> {
 
 // Obtener PageRank (tendencias)
 async getPageRank(): Promise<{ rankings: { name: string; score: number }[] }> {
-  const token = authService.getToken();
-  
-  const response = await fetch(`${API_URL}/pagerank`, {
+  const response = await authService.fetch(`/pagerank`, {
     method: 'GET',
     headers: {
-      'Authorization': `Bearer ${token}`,
       'Content-Type': 'application/json',
     },
   });
@@ -1,8 +1,13 @@
 // src/services/authService.tsx
 const API_URL = 'https://min.oxlo.io:6232';
 
+// Con COOKIE_SESSIONS el backend deja los tokens en cookies HttpOnly y solo
+// regresa el CSRF token, que hay que mandar en `X-CSRF-Token`
 interface LoginResponse {
-  token: string;
+  token?: string;
+  refresh_token?: string;
+  csrf_token?: string;
+  expires_in: number;
 }
 
 interface RegisterData {
@@ -19,15 +24,37 @@ interface AuthError {
   status: number;
 }
 
+const storeTokens = (data: LoginResponse) => {
+  if (data.token) {
+    window.sessionStorage.setItem('authToken', data.token);
+  }
+  if (data.refresh_token) {
+    window.sessionStorage.setItem('refreshToken', data.refresh_token);
+  }
+  if (data.csrf_token) {
+    window.sessionStorage.setItem('csrfToken', data.csrf_token);
+  }
+};
+
+const csrfHeader = (): Record<string, string> => {
+  const csrfToken = window.sessionStorage.getItem('csrfToken');
+  return csrfToken ? { 'X-CSRF-Token': csrfToken } : {};
+};
+
+// Un solo refresh a la vez, el backend invalida la sesion si el mismo
+// refresh token se usa dos veces
+let refreshing: Promise<boolean> | null = null;
+
 export const authService = {
-  async login(username: string, password: string): Promise<LoginResponse> {
+  async login(identifier: string, password: string): Promise<LoginResponse> {
     try {
       const response = await fetch(`${API_URL}/auth/signin`, {
         method: 'POST',
+        credentials: 'include',
         headers: {
           'Content-Type': 'application/json',
         },
-        body: JSON.stringify({ username, password }),
+        body: JSON.stringify({ identifier, password }),
       });
 
       if (response.status === 400) {
@@ -48,9 +75,7 @@ export const authService = {
 
       const data: LoginResponse = await response.json();
       
-      if (data.token) {
-        window.sessionStorage.setItem('authToken', data.token);
-      }
+      storeTokens(data);
       
       return data;
     } catch (error: unknown) {
@@ -94,8 +119,135 @@ export const authService = {
     }
   },
 
+  // Segundo paso cuando la cuenta tiene TOTP, acepta tambien codigos de recuperacion
+  async signinTotp(challenge: string, code: string): Promise<LoginResponse> {
+    try {
+      const response = await fetch(`${API_URL}/auth/signin/totp`, {
+        method: 'POST',
+        credentials: 'include',
+        headers: {
+          'Content-Type': 'application/json',
+        },
+        body: JSON.stringify({ challenge, code }),
+      });
+
+      if (response.status === 401 || response.status === 403) {
+        throw { message: 'Código incorrecto o expirado', status: response.status } as AuthError;
+      }
+
+      if (response.status === 429) {
+        throw { message: 'Demasiados intentos. Espera un momento', status: 429 } as AuthError;
+      }
+
+      if (!response.ok) {
+        throw { message: 'Error al iniciar sesión', status: response.status } as AuthError;
+      }
+
+      const data: LoginResponse = await response.json();
+
+      storeTokens(data);
+
+      return data;
+    } catch (error: unknown) {
+      if (error && typeof error === 'object' && 'status' in error) {
+        throw error;
+      }
+      throw { message: 'Error de conexión. Verifica tu internet', status: 0 } as AuthError;
+    }
+  },
+
+  // El backend regresa del provider de OIDC con los tokens en el fragmento,
+  // regresa si habia una sesion que guardar
+  completeOidc(params: URLSearchParams): boolean {
+    const data: LoginResponse = {
+      token: params.get('token') ?? undefined,
+      refresh_token: params.get('refresh_token') ?? undefined,
+      csrf_token: params.get('csrf_token') ?? undefined,
+      expires_in: Number(params.get('expires_in') ?? 0),
+    };
+
+    storeTokens(data);
+
+    return !!data.token || !!data.csrf_token;
+  },
+
+  async refresh(): Promise<boolean> {
+    const refreshToken = window.sessionStorage.getItem('refreshToken');
+    const cookieMode = !!window.sessionStorage.getItem('csrfToken');
+    if (!refreshToken && !cookieMode) {
+      return false;
+    }
+
+    if (!refreshing) {
+      refreshing = (async () => {
+        try {
+          // En modo cookies el refresh token viaja en su cookie y el cuerpo va vacio
+          const response = await fetch(`${API_URL}/auth/refresh`, {
+            method: 'POST',
+            credentials: 'include',
+            headers: refreshToken
+              ? { 'Content-Type': 'application/json' }
+              : csrfHeader(),
+            body: refreshToken ? JSON.stringify({ refresh_token: refreshToken }) : undefined,
+          });
+
+          if (!response.ok) {
+            return false;
+          }
+
+          storeTokens(await response.json());
+          return true;
+        } catch {
+          return false;
+        } finally {
+          refreshing = null;
+        }
+      })();
+    }
+
+    return refreshing;
+  },
+
+  // fetch con el token de acceso, si expiro lo renueva y reintenta una vez
+  async fetch(path: string, init: RequestInit = {}): Promise<Response> {
+    const send = () => {
+      const token = window.sessionStorage.getItem('authToken');
+      return fetch(`${API_URL}${path}`, {
+        ...init,
+        credentials: 'include',
+        headers: {
+          ...init.headers,
+          ...(token ? { 'Authorization': `Bearer ${token}` } : csrfHeader()),
+        },
+      });
+    };
+
+    const response = await send();
+    if (response.status !== 401) {
+      return response;
+    }
+
+    if (await authService.refresh()) {
+      return send();
+    }
+
+    authService.logout();
+    return response;
+  },
+
   logout(): void {
+    // Las cookies HttpOnly solo las puede borrar el backend
+    if (window.sessionStorage.getItem('csrfToken')) {
+      fetch(`${API_URL}/auth/signout`, {
+        method: 'POST',
+        credentials: 'include',
+        headers: csrfHeader(),
+      }).catch(() => {});
+    }
+
     window.sessionStorage.removeItem('authToken');
+    window.sessionStorage.removeItem('refreshToken');
+    window.sessionStorage.removeItem('csrfToken');
     window.sessionStorage.removeItem('orbitlyUser');
     
     // Limpiar todos los chats guardados
@@ -112,6 +264,6 @@ export const authService = {
 
   isAuthenticated(): boolean {
     const token = window.sessionStorage.getItem('authToken');
-    return !!token;
+    return !!token || !!window.sessionStorage.getItem('csrfToken');
   }
 };
\ No newline at end of file
@@ -0,0 +1,6 @@
+CREATE CONSTRAINT session_token_unique IF NOT EXISTS FOR (s:Session) REQUIRE s.token IS UNIQUE;
+// Un indice no evita que dos MERGE simultaneos creen la misma sesion dos veces
+DROP INDEX session_username IF EXISTS;
+CREATE CONSTRAINT session_user_id_unique IF NOT EXISTS FOR (s:Session) REQUIRE (s.username_key, s.id) IS UNIQUE;
+CREATE CONSTRAINT refresh_token_unique IF NOT EXISTS FOR (r:RefreshToken) REQUIRE r.token IS UNIQUE;
+CREATE CONSTRAINT pending_key_unique IF NOT EXISTS FOR (p:Pending) REQUIRE p.key IS UNIQUE;
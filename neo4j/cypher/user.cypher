@@ -1,2 +1,5 @@
 CREATE CONSTRAINT user_username_unique IF NOT EXISTS FOR (u:User) REQUIRE u.username IS UNIQUE;
 CREATE CONSTRAINT user_mail_unique IF NOT EXISTS FOR (u:User) REQUIRE u.mail IS UNIQUE;
+// Llaves normalizadas (NFKC + minusculas) para buscar sin importar mayusculas
+CREATE CONSTRAINT user_username_key_unique IF NOT EXISTS FOR (u:User) REQUIRE u.username_key IS UNIQUE;
+CREATE CONSTRAINT user_mail_key_unique IF NOT EXISTS FOR (u:User) REQUIRE u.mail_key IS UNIQUE;